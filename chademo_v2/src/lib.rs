@@ -42,6 +42,13 @@ pub struct X101 {
     rated_battery_capacity: f32,
 }
 
+impl X101 {
+    /// Rated battery capacity, 0.1 kWh/bit
+    pub fn rated_battery_capacity_kwh(&self) -> f32 {
+        self.rated_battery_capacity * 0.1
+    }
+}
+
 impl From<&CANFrame> for X101 {
     fn from(frame: &CANFrame) -> Self {
         let data = data_sanity(&frame, 0x101, 8);
@@ -510,6 +517,9 @@ impl X208 {
     pub fn get_lower_threshold_voltage(&self) -> u16 {
         self.lower_threshold_voltage
     }
+    pub fn set_lower_threshold_voltage(&mut self, volts: u16) {
+        self.lower_threshold_voltage = volts;
+    }
 }
 
 impl From<&CANFrame> for X208 {
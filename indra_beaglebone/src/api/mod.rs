@@ -1,11 +1,22 @@
 use crate::{
     chademo::state::CHADEMO,
     data_io::{
+        config::APP_CONFIG,
         db::{ChademoDbRow, Parameters},
         mqtt::{MqttChademo, CHADEMO_DATA},
+        wear::{LoadSwitchRow, WearStatus},
     },
     global_state::OperationMode,
     log_error,
+    pre_charger::{
+        energy::{EnergyDay, EnergyReport, ENERGY},
+        fans::{self, FanTest},
+        modules::{ModuleStatus, PRE_MODULES},
+    },
+    safety::{
+        estop::{self, LatchedFault, FAULT},
+        startup::{self, StartupReport, STARTUP},
+    },
     scheduler::{get_eventfile_sync, Events},
     statics::{ChademoTx, EventsTx, OPERATIONAL_MODE},
     POOL,
@@ -138,6 +149,36 @@ fn process_ws_message(
                 log::info!("GetEvents => Client {:?}", response);
                 Ok(Message::Text(serde_json::to_string(&response).unwrap()))
             }
+            Cmd::GetFault => match FAULT.clone().try_read() {
+                Ok(fault) => {
+                    let response = Response::Fault(fault.clone());
+                    log::info!("GetFault => Client {:?}", response);
+                    Ok(Message::Text(serde_json::to_string(&response).unwrap()))
+                }
+                Err(_) => Ok(Message::Text(BAD_ACK.to_string())),
+            },
+            Cmd::GetStartup => match STARTUP.clone().try_read() {
+                Ok(report) => {
+                    let response = Response::Startup(report.clone());
+                    log::info!("GetStartup => Client {:?}", response);
+                    Ok(Message::Text(serde_json::to_string(&response).unwrap()))
+                }
+                Err(_) => Ok(Message::Text(BAD_ACK.to_string())),
+            },
+            Cmd::AckFault => match estop::acknowledge() {
+                Ok(_) => Ok(Message::Text(r#"{"ack": "ok"}"#.to_string())),
+                Err(e) => {
+                    log::error!("AckFault refused {e}");
+                    Ok(Message::Text(BAD_ACK.to_string()))
+                }
+            },
+            Cmd::AckStartup => match startup::acknowledge() {
+                Ok(_) => Ok(Message::Text(r#"{"ack": "ok"}"#.to_string())),
+                Err(e) => {
+                    log::error!("AckStartup refused {e}");
+                    Ok(Message::Text(BAD_ACK.to_string()))
+                }
+            },
             Cmd::GetRecords(params) => {
                 let handle = async move {
                     if let Some(db) = POOL.get() {
@@ -155,6 +196,97 @@ fn process_ws_message(
                 let rt = tokio::runtime::Runtime::new().unwrap();
                 rt.block_on(async { tokio::spawn(handle).await.unwrap() })
             }
+            Cmd::GetWear => {
+                let handle = async move {
+                    match POOL.get().map(|db| db.get_wear_records()) {
+                        Some(query) => match query.await {
+                            Ok(rows) => {
+                                let status = rows
+                                    .into_iter()
+                                    .map(|row| WearStatus::new(row, &APP_CONFIG.wear))
+                                    .collect();
+                                let response = Response::Wear(status);
+                                log::info!("GetWear => Client {:?}", response);
+                                Ok(Message::Text(serde_json::to_string(&response).unwrap()))
+                            }
+                            Err(_) => Ok(Message::Text(BAD_ACK.to_string())),
+                        },
+                        None => Ok(Message::Text(BAD_ACK.to_string())),
+                    }
+                };
+                let rt = tokio::runtime::Runtime::new().unwrap();
+                rt.block_on(async { tokio::spawn(handle).await.unwrap() })
+            }
+            Cmd::GetLoadSwitches => {
+                let handle = async move {
+                    match POOL.get().map(|db| db.get_load_switches()) {
+                        Some(query) => match query.await {
+                            Ok(rows) => {
+                                log::info!(
+                                    "GetLoadSwitches => Client, {} rows returned",
+                                    rows.len()
+                                );
+                                let response = Response::LoadSwitches(rows);
+                                Ok(Message::Text(serde_json::to_string(&response).unwrap()))
+                            }
+                            Err(_) => Ok(Message::Text(BAD_ACK.to_string())),
+                        },
+                        None => Ok(Message::Text(BAD_ACK.to_string())),
+                    }
+                };
+                let rt = tokio::runtime::Runtime::new().unwrap();
+                rt.block_on(async { tokio::spawn(handle).await.unwrap() })
+            }
+            Cmd::FanTest(duty) => {
+                let handle = async move {
+                    match fans::fan_test(duty, &APP_CONFIG.fan).await {
+                        Ok(test) => {
+                            let response = Response::FanTest(test);
+                            log::info!("FanTest => Client {:?}", response);
+                            Ok(Message::Text(serde_json::to_string(&response).unwrap()))
+                        }
+                        Err(e) => {
+                            log::error!("FanTest refused {e}");
+                            Ok(Message::Text(BAD_ACK.to_string()))
+                        }
+                    }
+                };
+                let rt = tokio::runtime::Runtime::new().unwrap();
+                rt.block_on(async { tokio::spawn(handle).await.unwrap() })
+            }
+            Cmd::GetEnergy => match ENERGY.lock() {
+                Ok(energy) => {
+                    let response = Response::Energy(energy.report());
+                    log::info!("GetEnergy => Client {:?}", response);
+                    Ok(Message::Text(serde_json::to_string(&response).unwrap()))
+                }
+                Err(_) => Ok(Message::Text(BAD_ACK.to_string())),
+            },
+            Cmd::GetPreModules => match PRE_MODULES.clone().try_lock() {
+                Ok(modules) => {
+                    let response = Response::PreModules(modules.clone());
+                    log::info!("GetPreModules => Client {:?}", response);
+                    Ok(Message::Text(serde_json::to_string(&response).unwrap()))
+                }
+                Err(_) => Ok(Message::Text(BAD_ACK.to_string())),
+            },
+            Cmd::GetEnergyDays(days) => {
+                let handle = async move {
+                    match POOL.get().map(|db| db.get_energy_days(days)) {
+                        Some(query) => match query.await {
+                            Ok(rows) => {
+                                log::info!("GetEnergyDays => Client, {} rows returned", rows.len());
+                                let response = Response::EnergyDays(rows);
+                                Ok(Message::Text(serde_json::to_string(&response).unwrap()))
+                            }
+                            Err(_) => Ok(Message::Text(BAD_ACK.to_string())),
+                        },
+                        None => Ok(Message::Text(BAD_ACK.to_string())),
+                    }
+                };
+                let rt = tokio::runtime::Runtime::new().unwrap();
+                rt.block_on(async { tokio::spawn(handle).await.unwrap() })
+            }
         },
         Err(e) => {
             log::error!("Could not deserialise Instruction {cmd} - {e:?}");
@@ -169,6 +301,15 @@ fn process_ws_message(
 // {"cmd": "GetJson"}
 // {"cmd": "GetEvents"}
 // {"cmd": {"SetEvents": [{"time": "00:01:02", "Action": "Charge"}, {"time": "00:02:32", "Action": "V2h"}]}}
+// {"cmd": "GetFault"}
+// {"cmd": "AckFault"}
+// {"cmd": "GetWear"}
+// {"cmd": "GetLoadSwitches"}
+// {"cmd": "GetStartup"}
+// {"cmd": {"FanTest": 60}}
+// {"cmd": "GetEnergy"}
+// {"cmd": {"GetEnergyDays": 7}}
+// {"cmd": "GetPreModules"}
 
 // pub enum Action {
 //     Charge,
@@ -248,6 +389,19 @@ enum Cmd {
     SetEvents(Events),
     GetEvents,
     GetRecords(Parameters),
+    GetFault,
+    AckFault,
+    GetWear,
+    GetLoadSwitches,
+    GetStartup,
+    /// Clears startup self-check faults
+    AckStartup,
+    /// Duty %
+    FanTest(u8),
+    GetEnergy,
+    /// Days back from now
+    GetEnergyDays(u32),
+    GetPreModules,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug)]
@@ -260,6 +414,14 @@ enum Response {
     Mode(OperationMode),
     Events(Events),
     Records(Vec<ChademoDbRow>),
+    Fault(Option<LatchedFault>),
+    Wear(Vec<WearStatus>),
+    LoadSwitches(Vec<LoadSwitchRow>),
+    Startup(StartupReport),
+    FanTest(FanTest),
+    Energy(EnergyReport),
+    EnergyDays(Vec<EnergyDay>),
+    PreModules(Vec<ModuleStatus>),
 }
 
 /*
@@ -0,0 +1,66 @@
+/// Power smoothing factor per 100ms cycle
+const ALPHA: f32 = 0.05;
+/// Below this no estimate is made
+const MIN_POWER_W: f32 = 100.0;
+
+/// Smoothed power for remaining time estimates
+#[derive(Default, Debug, Clone, Copy)]
+pub struct TimeEstimator {
+    power_w: f32,
+}
+
+impl TimeEstimator {
+    /// Feeds measured power (negative is discharge), returns smoothed power
+    pub fn update(&mut self, power_w: f32) -> f32 {
+        self.power_w += (power_w - self.power_w) * ALPHA;
+        self.power_w
+    }
+    pub fn power(&self) -> f32 {
+        self.power_w
+    }
+    pub fn reset(&mut self) {
+        self.power_w = 0.0
+    }
+}
+
+/// Minutes until target_soc is reached at power_w, negative power is discharge
+pub fn minutes_to_target(capacity_kwh: f32, soc: u8, target_soc: u8, power_w: f32) -> Option<u16> {
+    if capacity_kwh <= 0.0 || power_w.abs() < MIN_POWER_W {
+        return None;
+    }
+    let delta = match power_w.is_sign_negative() {
+        true => soc.saturating_sub(target_soc),
+        false => target_soc.saturating_sub(soc),
+    };
+    let kwh = capacity_kwh * delta as f32 / 100.0;
+    let minutes = kwh * 1000.0 / power_w.abs() * 60.0;
+    Some(minutes.round().min(u16::MAX as f32) as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn minutes_to_target_test() {
+        // 40kWh, 50% -> 90% at 4kW = 16kWh = 4h
+        assert_eq!(minutes_to_target(40.0, 50, 90, 4000.0), Some(240));
+        // 40kWh, 50% -> 30% at -2kW = 8kWh = 4h
+        assert_eq!(minutes_to_target(40.0, 50, 30, -2000.0), Some(240));
+        // already at target
+        assert_eq!(minutes_to_target(40.0, 90, 90, 4000.0), Some(0));
+        assert_eq!(minutes_to_target(40.0, 20, 30, -2000.0), Some(0));
+        // no capacity reported or no power flowing
+        assert_eq!(minutes_to_target(0.0, 50, 90, 4000.0), None);
+        assert_eq!(minutes_to_target(40.0, 50, 90, 10.0), None);
+    }
+    #[test]
+    fn estimator_test() {
+        let mut estimator = TimeEstimator::default();
+        for _ in 0..200 {
+            estimator.update(-3000.0);
+        }
+        assert!((estimator.power() + 3000.0).abs() < 1.0);
+        estimator.reset();
+        assert_eq!(estimator.power(), 0.0);
+    }
+}
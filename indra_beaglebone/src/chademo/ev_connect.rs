@@ -4,19 +4,31 @@ use crate::{
         can::*,
         state::{Chademo, *}, //ChargerState
     },
-    data_io::{mqtt::CHADEMO_DATA, panel::LedCommand},
+    data_io::{
+        config::{FanFaultAction, PrechargeConfig, APP_CONFIG},
+        mqtt::CHADEMO_DATA,
+        panel::LedCommand,
+        wear::{self, Device},
+    },
     error::IndraError,
     global_state::{ChargeParameters, OperationMode},
     log_error,
     meter::METER,
     pre_charger::{
-        fans::Fan,
+        derate::ThermalDerate,
+        fans::FAN,
         pre_thread::{self},
-        pwm::Pwm,
-        PreCharger, PreCommand, BB_PWM_CHIP, BB_PWM_NUMBER, PREDATA,
+        slew::SlewLimiter,
+        status::{FaultHandler, Reaction},
+        supervisor::CommsState,
+        PreCharger, PreCommand, PREDATA,
+    },
+    safety::{
+        estop, feedback,
+        startup::{self, SessionPhase},
     },
     statics::{self, *},
-    timeout_condition, MAX_AMPS, MAX_SOC, METER_BIAS, MIN_SOC,
+    timeout_condition, METER_BIAS,
 };
 use chademo_v2::{X109Status, X108};
 use log::warn;
@@ -29,6 +41,30 @@ use tokio::{
 use tokio_socketcan::{CANFrame, CANSocket};
 
 const DUMMYMODE: bool = false;
+/// Longest a stop waits for the amps setpoint to ramp to 0A before shutting the Pre down
+const STOP_RAMP_LIMIT: Duration = Duration::from_secs(5);
+
+/// Charge loop exit held until the amps setpoint has ramped down
+#[derive(Debug, Default, Clone, Copy)]
+struct StopRamp {
+    since: Option<Instant>,
+}
+
+impl StopRamp {
+    fn start(&mut self, now: Instant) {
+        self.since.get_or_insert(now);
+    }
+    fn is_active(&self) -> bool {
+        self.since.is_some()
+    }
+    /// Ramp reached 0A or ran out of time
+    fn is_done(&self, amps: f32, now: Instant) -> bool {
+        match self.since {
+            Some(since) => amps == 0.0 || now.duration_since(since) > STOP_RAMP_LIMIT,
+            None => false,
+        }
+    }
+}
 
 pub async fn ev100ms(led_tx: LedTx, mode_rx: ChademoRx) -> Result<(), IndraError> {
     log::info!("Starting EV thread");
@@ -42,21 +78,18 @@ pub async fn ev100ms(led_tx: LedTx, mode_rx: ChademoRx) -> Result<(), IndraError
     let mode_rx = mutex(mode_rx);
     use tokio::task::JoinHandle;
     let mut handles: Vec<JoinHandle<Result<(), IndraError>>> = Vec::new(); // Store spawned task handles
+    check_pre_output(&mut chademo, &predata, &pre_rx).await;
     loop {
         for handle in handles.drain(..) {
             log::info!("Aborting Pre thread {}", handle.id());
             handle.abort(); // Abort the previous tasks
         }
-        reset_gpio_state(&mut chademo);
+        reset_gpio_state(&mut chademo).await;
+        startup::set_phase(SessionPhase::Idle);
         chademo.set_state(OperationMode::Idle);
         update_panel_leds(&led_tx, &chademo).await;
         update_chademo_mutex(&chademo).await;
-        {
-            // fan fudge
-            let pwm = Pwm::new(BB_PWM_CHIP, BB_PWM_NUMBER, 1000).unwrap(); // number depends on chip, etc.
-            Fan::new(pwm).update(10.0);
-            // fan.update(10.0);
-        }
+        FAN.lock().await.stop();
         let mut can = tokio_socketcan::CANSocket::open(&"can1").map_err(|_| IndraError::Error)?;
         {
             if let Some(state) = mode_rx.clone().lock().await.recv().await {
@@ -66,6 +99,25 @@ pub async fn ev100ms(led_tx: LedTx, mode_rx: ChademoRx) -> Result<(), IndraError
                 if !(state.is_v2h() || state.is_charge()) {
                     continue;
                 }
+                if estop::is_latched().await {
+                    log::error!("{:?} refused - {}", state, IndraError::FaultLatched);
+                    chademo.set_state(OperationMode::Idle);
+                    update_panel_leds(&led_tx, &chademo).await;
+                    update_chademo_mutex(&chademo).await;
+                    continue;
+                }
+                // unattended, needs the Pre output checked since start
+                if state.is_v2h() && !startup::is_confirmed().await {
+                    // skipped at start while faults awaited acknowledgement
+                    check_pre_output(&mut chademo, &predata, &pre_rx).await;
+                }
+                if state.is_v2h() && !startup::is_confirmed().await {
+                    log::error!("{:?} refused - {}", state, IndraError::StartupFault);
+                    chademo.set_state(OperationMode::Idle);
+                    update_panel_leds(&led_tx, &chademo).await;
+                    update_chademo_mutex(&chademo).await;
+                    continue;
+                }
                 if matches!(state, OperationMode::Quit) {
                     return Ok(());
                 }
@@ -91,18 +143,45 @@ pub async fn ev100ms(led_tx: LedTx, mode_rx: ChademoRx) -> Result<(), IndraError
         log::info!("Spawned new Pre thread {}", handle.id());
         handles.push(handle);
         chademo.charge_stop();
-        chademo.pins().pre_ac.set_value(1).unwrap();
-        if let Err(e) = init_pre(&predata, t100ms, &pre_tx).await {
+        startup::set_phase(SessionPhase::PreInit);
+        if let Err(e) = estop::ensure_clear().await {
+            log::error!("{e}");
+            chademo.set_state(OperationMode::Idle);
+            reset_gpio_state(&mut chademo).await;
+            update_chademo_mutex(&chademo).await;
+            continue;
+        }
+        wear::switch(Device::PreAc, &chademo.pins().pre_ac, 1, 0.0).unwrap();
+        if let Err(e) = feedback::verify(Device::PreAc, true, &APP_CONFIG.feedback).await {
+            log::error!("{e}");
+
+            chademo.set_state(OperationMode::Idle);
+            reset_gpio_state(&mut chademo).await;
+            update_chademo_mutex(&chademo).await;
+            continue;
+        };
+        if let Err(e) = init_pre(&predata, t100ms, &pre_tx, &APP_CONFIG.precharge).await {
             log::error!("Pre init failed - {e:?}");
 
             chademo.set_state(OperationMode::Idle);
-            reset_gpio_state(&mut chademo);
+            reset_gpio_state(&mut chademo).await;
             update_chademo_mutex(&chademo).await;
             continue;
         };
+        // offered to the EV in x108 during the handshake, the Pre thread
+        // hasn't reported its active modules yet so all configured are assumed
+        chademo.set_max_amps(APP_CONFIG.pre_modules.max_amps());
         chademo.x109.status = X109Status::from(0x20);
         assert!(!chademo.x109.status.status_station);
         assert!(!chademo.x109.status.status_vehicle_connector_lock);
+        startup::set_phase(SessionPhase::Handshake);
+        if let Err(e) = estop::ensure_clear().await {
+            log::error!("{e}");
+            chademo.set_state(OperationMode::Idle);
+            reset_gpio_state(&mut chademo).await;
+            update_chademo_mutex(&chademo).await;
+            continue;
+        }
         log::info!("Raise D1");
         log_error!("Setting D1 high", chademo.pins().d1.set_value(1));
 
@@ -111,31 +190,47 @@ pub async fn ev100ms(led_tx: LedTx, mode_rx: ChademoRx) -> Result<(), IndraError
             log::error!("K line init failed - is car connected? {e:?}");
 
             chademo.set_state(OperationMode::Idle);
-            reset_gpio_state(&mut chademo);
+            reset_gpio_state(&mut chademo).await;
             update_chademo_mutex(&chademo).await;
             continue;
         };
 
-        chademo.plug_lock(true).expect("Plug lock failed");
+        if let Err(e) = chademo.negotiate_limits() {
+            fail_session(&mut can, &mut chademo, e).await;
+            continue;
+        };
+
+        if let Err(e) = estop::ensure_clear().await {
+            fail_session(&mut can, &mut chademo, e).await;
+            continue;
+        };
+        if let Err(e) = chademo.plug_lock(true, &APP_CONFIG.feedback).await {
+            fail_session(&mut can, &mut chademo, e).await;
+            continue;
+        };
+        startup::set_phase(SessionPhase::PlugLocked);
         assert!(!chademo.x109.status.status_station);
         assert!(chademo.x109.status.status_vehicle_connector_lock);
         // update_chademo_mutex(&chademo).await;
         // chademo.precharge();
         log::info!("insulation tests skipped !!!");
+        if let Err(e) = estop::ensure_clear().await {
+            fail_session(&mut can, &mut chademo, e).await;
+            continue;
+        };
         chademo.pins().d2.set_value(1).unwrap();
 
         // update_chademo_mutex(&chademo).await;
         log::info!("when voltage match - raise D2");
         chademo.charge_start();
+        startup::set_phase(SessionPhase::Precharge);
         if let Err(e) = precharge(&mut can, &mut chademo, &pre_tx, &predata).await {
             log::error!("precharge & contactor init failed - should be catastropic and hang {e:?}");
-
-            chademo.set_state(OperationMode::Idle);
-            reset_gpio_state(&mut chademo);
-            update_chademo_mutex(&chademo).await;
+            fail_session(&mut can, &mut chademo, e).await;
             continue;
         }
         log::info!("precharge left");
+        startup::set_phase(SessionPhase::Energised);
         chademo.x109.status = X109Status::from(0x05);
         assert!(chademo.x109.status.status_vehicle_connector_lock);
         assert!(chademo.x109.status.status_station);
@@ -159,10 +254,12 @@ pub async fn ev100ms(led_tx: LedTx, mode_rx: ChademoRx) -> Result<(), IndraError
         chademo.x109.status = X109Status::from(0x24);
         // chademo.charging_stop_control_release();
         log_error!("Shutdown pre", pre_tx.send(PreCommand::Shutdown).await);
+        startup::set_phase(SessionPhase::Shutdown);
         shutdown(&mut chademo, &mut can).await;
         log::warn!("Charge/discharge mode ended");
         update_chademo_mutex(&chademo).await;
         if matches!(exit_reason, OperationMode::Quit) {
+            startup::set_phase(SessionPhase::Idle);
             return Ok(());
         }
         drop(can);
@@ -196,10 +293,15 @@ async fn shutdown(chademo: &mut Chademo, can: &mut CANSocket) {
         };
         if contactors {
             log::info!("Contactors opening");
-            if chademo.pins().c1.set_value(0).is_ok() {
+            let amps = pre_output_amps().await;
+            if wear::switch(Device::C1, &chademo.pins().c1, 0, amps).is_ok() {
                 print!("\x07");
-                if chademo.pins().c2.set_value(0).is_ok() {
+                if wear::switch(Device::C2, &chademo.pins().c2, 0, amps).is_ok() {
                     print!("\x07");
+                    if let Err(e) = chademo.verify_contactors(false, &APP_CONFIG.feedback).await {
+                        // possibly welded, cut the master contactor until acknowledged
+                        estop::latch(&e.to_string()).await;
+                    }
                     warn!("                                       !!!!CONTACTORS OPEN!!!!");
 
                     contactors = false;
@@ -217,19 +319,49 @@ async fn shutdown(chademo: &mut Chademo, can: &mut CANSocket) {
                                                               // if !chademo.x102.status.status_vehicle {
             } //     break;
               // }
-            log_error!("Pluglock disable", chademo.plug_lock(false));
+            if let Err(e) = chademo.plug_lock(false, &APP_CONFIG.feedback).await {
+                // lock still reported to EV, don't retry forever
+                log::error!("Pluglock disable {e}");
+                break;
+            }
         }
     }
 }
-fn reset_gpio_state(chademo: &mut Chademo) {
-    log_error!("Exit charge: c2", chademo.pins().c2.set_value(0));
-    log_error!("Exit charge: c1", chademo.pins().c1.set_value(0));
+
+/// Flags stop to the EV for 1s then idles, any 109 faults set are sent with it
+async fn fail_session(can: &mut CANSocket, chademo: &mut Chademo, e: IndraError) {
+    log::error!("{e}");
+    chademo.request_stop_charge();
+    for _ in 0..10 {
+        log_error!("Session failed", recv_send(can, chademo, false).await);
+    }
+    chademo.set_state(OperationMode::Idle);
+    reset_gpio_state(chademo).await;
+    update_chademo_mutex(chademo).await;
+}
+/// Live Pre output, the load on the DC contactors as they open
+async fn pre_output_amps() -> f32 {
+    PREDATA.lock().await.get_dc_output_amps()
+}
+async fn reset_gpio_state(chademo: &mut Chademo) {
+    let amps = pre_output_amps().await;
+    log_error!(
+        "Exit charge: c2",
+        wear::switch(Device::C2, &chademo.pins().c2, 0, amps)
+    );
+    log_error!(
+        "Exit charge: c1",
+        wear::switch(Device::C1, &chademo.pins().c1, 0, amps)
+    );
     log_error!("Exit charge: d2", chademo.pins().d2.set_value(0));
     log_error!("Exit charge: d1", chademo.pins().d1.set_value(0));
-    log_error!("Exit charge: Pre AC", chademo.pins().pre_ac.set_value(0));
+    log_error!(
+        "Exit charge: Pre AC",
+        wear::switch(Device::PreAc, &chademo.pins().pre_ac, 0, amps)
+    );
     log_error!(
         "Exit charge: pluglock",
-        chademo.pins().pluglock.set_value(0)
+        wear::switch(Device::PlugLock, &chademo.pins().pluglock, 0, 0.0)
     );
     chademo.x109.status = X109Status::from(0x24);
 }
@@ -243,23 +375,69 @@ async fn charge_mode(
 ) -> Result<OperationMode, IndraError> {
     let mut mode_rx = mode_rx.lock().await;
     let mut last_soc = *chademo.soc();
-    let mut last_volts = 0.0;
-    let mut last_amps = 1.0;
     let mut last_meter = 0.01;
     let mut counter = 0;
+    let mut pre_faults = FaultHandler::default();
+    // ramps start from the setpoints left by precharge
+    let (mut amps_slew, mut volts_slew) = {
+        let pre = *PREDATA.clone().lock().await;
+        (
+            SlewLimiter::amps(&APP_CONFIG.slew, pre.get_dc_setpoint_amps()),
+            SlewLimiter::volts(&APP_CONFIG.slew, pre.get_dc_setpoint_volts()),
+        )
+    };
+    let mut last_volts = volts_slew.output();
+    let mut last_amps = amps_slew.output();
+    let mut last_step = Instant::now();
+    let mut stop_ramp = StopRamp::default();
     use crate::global_state::OperationMode::*;
 
     let exit_reason = loop {
+        let (reaction, comms, fan_fault) = {
+            // report live Pre output to EV in outgoing x109/x208
+            let pre = *PREDATA.clone().lock().await;
+            chademo.update_measured(pre.get_dc_output_volts(), pre.get_dc_output_amps());
+            let mut derate = ThermalDerate::from_temp(pre.get_temp(), &APP_CONFIG.derate);
+            if pre.get_fan().fault.is_some() {
+                derate = derate.cap(APP_CONFIG.fan.fault_derate);
+            }
+            // isolated modules take their share of output with them
+            chademo.set_max_amps(module_limit(&pre));
+            chademo.apply_derate(derate);
+            let reaction = pre_faults.update(*pre.get_status(), Instant::now().into_std());
+            (reaction, pre.get_comms().state, pre.get_fan().fault)
+        };
         if DUMMYMODE {
             sleep(Duration::from_millis(100)).await
         } else {
             recv_send(can, chademo, false).await?;
-            if !chademo.status_vehicle_charging() {
+            if !chademo.status_vehicle_charging() && !stop_ramp.is_active() {
                 log::warn!("EV stopped charge");
                 dbg!(&chademo);
-                break Idle;
+                stop_ramp.start(Instant::now());
             }
         };
+        // no ramp, the master contactor is already open
+        if estop::is_latched().await {
+            log::error!("Latched fault - leaving charge loop");
+            break Idle;
+        }
+        if !stop_ramp.is_active() {
+            let fault = match fan_fault {
+                _ if comms.is_lost() => Some(IndraError::PreCommsLost.to_string()),
+                Some(fan) if APP_CONFIG.fan.on_fault == FanFaultAction::Stop => {
+                    Some(format!("Fan {fan:?}"))
+                }
+                _ if reaction == Reaction::Stop => Some("Pre fault".to_string()),
+                _ => None,
+            };
+            if let Some(fault) = fault {
+                log::error!("{fault} - ramping down & leaving charge loop");
+                chademo.x109.status.fault_charging_system_malfunction = true;
+                chademo.request_stop_charge();
+                stop_ramp.start(Instant::now());
+            }
+        }
 
         if counter > 10 || counter == 0 {
             let x102status: u8 = chademo.x102.status.into();
@@ -285,16 +463,23 @@ async fn charge_mode(
             }
             // update_panel_leds(&led_tx, &chademo).await
         }
+        chademo.update_soc_window(&APP_CONFIG.soc);
+        chademo.update_time_to_target();
 
         let op = chademo.state();
+        let dt = last_step.elapsed();
+        last_step = Instant::now();
 
+        // stops ramp down to 0A while the EV ends the session
         let charging_current_request = match *op {
+            // EV or fault stop, ramp down before the Pre is shut down
+            _ if stop_ramp.is_active() => 0.0,
             V2h => amps_meter_profiler(&mut last_meter, &last_amps, &*chademo).await?,
             Discharge(d) => match handle_discharge_mode(&d, &chademo).await {
                 Some(amps) => amps,
                 None => {
                     chademo.request_stop_charge();
-                    continue;
+                    0.0
                 }
             },
             Charge(c) => match c.get_eco() {
@@ -302,22 +487,29 @@ async fn charge_mode(
                     Some(amps) => amps,
                     None => {
                         chademo.request_stop_charge();
-                        continue;
+                        0.0
                     }
                 },
                 true => amps_meter_profiler(&mut last_meter, &last_amps, &*chademo)
                     .await?
-                    .clamp(0.0, MAX_AMPS as f32), //
+                    .clamp(0.0, chademo.max_amps()), //
             },
             Quit | Idle => {
                 chademo.request_stop_charge();
-                continue;
+                0.0
             }
             _ => continue,
         };
+        let charging_current_request = match reaction {
+            Reaction::Derate(factor) => charging_current_request * factor,
+            Reaction::Hold => 0.0,
+            _ => charging_current_request,
+        };
+        let charging_current_request = chademo.derate().limit(charging_current_request);
 
-        if &last_volts != chademo.target_voltage() {
-            last_volts = *chademo.target_voltage();
+        let volts = volts_slew.step(*chademo.target_voltage(), dt);
+        if last_volts != volts {
+            last_volts = volts;
             log_error!(
                 "",
                 pre_tx.send(PreCommand::DcVoltsSetpoint(last_volts)).await
@@ -327,18 +519,20 @@ async fn charge_mode(
         // testing!!!!!!!
         // chademo.update_dynamic_charge_limits(charging_current_request);
         // let charging_current_request = chademo.x102.charging_current_request as f32;
-        if last_amps != charging_current_request {
-            last_amps = charging_current_request;
-            log_error!(
-                "",
-                pre_tx
-                    .send(PreCommand::DcAmpsSetpoint(charging_current_request))
-                    .await
-            );
+        let amps = amps_slew.step(charging_current_request, dt);
+        if last_amps != amps {
+            last_amps = amps;
+            log_error!("", pre_tx.send(PreCommand::DcAmpsSetpoint(amps)).await);
 
             update_chademo_mutex(&*chademo).await;
             update_panel_leds(&led_tx, &chademo).await
         }
+        if stop_ramp.is_done(amps, Instant::now()) {
+            if amps != 0.0 {
+                log::error!("Stop ramp timed out at {amps:.1}A");
+            }
+            break Idle;
+        }
         if &last_soc != chademo.soc() {
             last_soc = *chademo.soc();
             update_chademo_mutex(&*chademo).await;
@@ -349,33 +543,43 @@ async fn charge_mode(
 }
 
 async fn handle_charge_mode(cp: &ChargeParameters, chademo: &Chademo) -> Option<f32> {
-    let mut amps = Some((cp.get_amps() as f32).min(chademo.requested_charging_amps()));
-    if let Some(soc_limit) = cp.get_soc_limit() {
-        if &soc_limit <= chademo.soc() {
-            amps = None;
-            log::info!("Charge to SoC limit hit, charging disabled")
-        }
+    let window = chademo.soc_window();
+    if !window.can_charge(*chademo.soc()) {
+        log::info!(
+            "Charge to SoC limit {}% ({:?}) hit, charging disabled",
+            window.max,
+            window.max_source
+        );
+        return None;
     }
-    amps
+    Some(user_amps(cp, chademo).min(chademo.requested_charging_amps()))
 }
 async fn handle_discharge_mode(cp: &ChargeParameters, chademo: &Chademo) -> Option<f32> {
-    // note negative Some()
-    let mut amps = Some(-(cp.get_amps() as f32).min(chademo.requested_discharging_amps()));
-    if let Some(soc_limit) = cp.get_soc_limit() {
-        if &soc_limit <= chademo.soc() {
-            amps = None;
-            log::info!("Charge to SoC limit hit, charging disabled")
-        }
+    let window = chademo.soc_window();
+    if !window.can_discharge(*chademo.soc()) {
+        log::info!(
+            "Discharge to SoC limit {}% ({:?}) hit, discharging disabled",
+            window.min,
+            window.min_source
+        );
+        return None;
     }
-    amps
+    // note negative Some()
+    Some(-user_amps(cp, chademo).min(chademo.requested_discharging_amps()))
+}
+/// Requested limit within what the Pre modules in use can supply
+fn user_amps(cp: &ChargeParameters, chademo: &Chademo) -> f32 {
+    let max = chademo.max_amps();
+    cp.get_amps().map_or(max, |amps| (amps as f32).min(max))
+}
+/// Output of the modules still sharing current
+fn module_limit(pre: &PreCharger) -> f32 {
+    pre.get_modules()
+        .max_amps(APP_CONFIG.pre_modules.module_amps)
 }
 
-async fn init_pre(
-    predata: &std::sync::Arc<tokio::sync::Mutex<crate::pre_charger::PreCharger>>,
-    t100ms: Duration,
-    pre_tx: &tokio::sync::mpsc::Sender<PreCommand>,
-) -> Result<(), IndraError> {
-    log::info!("Initalise PRE");
+/// Waits for the Pre thread to bring the Pre online
+async fn pre_online(predata: &Arc<Mutex<PreCharger>>) -> Result<(), IndraError> {
     let mut c = false;
     let mut counter = 0;
     while !c {
@@ -384,28 +588,90 @@ async fn init_pre(
             return Err(IndraError::Timeout);
         }
         sleep(Duration::from_millis(1000)).await;
+        estop::ensure_clear().await?;
+        // a power cycle in progress counts towards the timeout too
         counter += 1;
         let pre = predata.lock().await;
+        match pre.get_comms().state {
+            CommsState::Lost => return Err(IndraError::PreCommsLost),
+            CommsState::Recovering => continue,
+            _ => (),
+        }
         c = pre.get_state().is_online()
     }
+    Ok(())
+}
+
+/// Completes startup::self_check, powers the Pre just long enough to read its DC output
+async fn check_pre_output(
+    chademo: &mut Chademo,
+    predata: &Arc<Mutex<PreCharger>>,
+    pre_rx: &PreRxMutex,
+) {
+    if !startup::is_safe().await || estop::is_latched().await {
+        log::warn!("Pre output check skipped until faults are acknowledged");
+        return;
+    }
+    log::info!("Pre output check");
+    let handle = tokio::spawn(pre_thread::init(pre_rx.clone()));
+    let result = async {
+        wear::switch(Device::PreAc, &chademo.pins().pre_ac, 1, 0.0)
+            .map_err(|e| IndraError::PinAccess(e))?;
+        feedback::verify(Device::PreAc, true, &APP_CONFIG.feedback).await?;
+        pre_online(predata).await?;
+        let volts = predata.lock().await.get_dc_output_volts();
+        startup::check_output_volts(volts).await
+    }
+    .await;
+    log_error!("Pre output check", result);
+    log_error!(
+        "Pre output check: Pre AC",
+        wear::switch(Device::PreAc, &chademo.pins().pre_ac, 0, 0.0)
+    );
+    handle.abort();
+}
+
+async fn init_pre(
+    predata: &std::sync::Arc<tokio::sync::Mutex<crate::pre_charger::PreCharger>>,
+    t100ms: Duration,
+    pre_tx: &tokio::sync::mpsc::Sender<PreCommand>,
+    config: &PrechargeConfig,
+) -> Result<(), IndraError> {
+    log::info!("Initalise PRE");
+    pre_online(predata).await?;
     log::info!("Pre stage 1");
+    // before any setpoint, a live output here means the DC bus was left energised
+    let volts = predata.lock().await.get_dc_output_volts();
+    startup::check_output_volts(volts).await?;
     log_error!("", pre_tx.send(PreCommand::DcAmpsSetpoint(1.0)).await);
     sleep(t100ms).await;
-    log_error!("", pre_tx.send(PreCommand::DcVoltsSetpoint(370.0)).await);
+    log_error!(
+        "",
+        pre_tx
+            .send(PreCommand::DcVoltsSetpoint(config.init_volts))
+            .await
+    );
     sleep(t100ms).await;
 
-    c = false;
-    counter = 0;
+    let mut c = false;
+    let mut counter = 0;
     while !c {
         if counter > 5 {
             log::error!("Initalise PRE stage 2 timed out after {counter}s");
             return Err(IndraError::Timeout);
         }
         sleep(Duration::from_millis(1000)).await;
+        estop::ensure_clear().await?;
         counter += 1;
         let pre = predata.lock().await;
-        if pre.get_dc_setpoint_volts() as u16 == 370 && pre.get_dc_setpoint_amps() as u16 == 1 {
-            c = pre.volts_equal();
+        if pre.get_comms().state.is_lost() {
+            return Err(IndraError::PreCommsLost);
+        }
+        // amps are split across modules, the readback sum may be a little short
+        if pre.get_dc_setpoint_volts() as u16 == config.init_volts as u16
+            && pre.get_dc_setpoint_amps().round() as u16 == 1
+        {
+            c = pre.volts_equal(config.tolerance);
         };
     }
 
@@ -420,6 +686,7 @@ async fn k_line(can: &mut CANSocket, chademo: &mut Chademo) -> Result<(), IndraE
         //100ms loop
         // log::debug!("K-loop {counter}");
         recv_send(can, chademo, false).await?;
+        estop::ensure_clear().await?;
 
         // log::info!("{}", chademo.x102.status);
         if chademo.k_line_check()? {
@@ -447,12 +714,19 @@ async fn precharge(
     pre_tx: &tokio::sync::mpsc::Sender<PreCommand>,
     predata: &Arc<Mutex<PreCharger>>,
 ) -> Result<(), IndraError> {
+    let config = &APP_CONFIG.precharge;
     let mut old_soc = 255;
+    let mut old_volts = 0.0;
     let mut counter = 100u8;
     while counter != 0 {
         counter -= 1;
         recv_send(can, chademo, true).await?;
+        estop::ensure_clear().await?;
         log::debug!("Counter {counter}");
+        if predata.lock().await.get_comms().state.is_lost() {
+            chademo.x109.status.fault_charging_system_malfunction = true;
+            return Err(IndraError::PreCommsLost);
+        }
         let x102status: u8 = chademo.x102.status.into();
         let x109status: u8 = chademo.x109.status.into();
 
@@ -464,13 +738,13 @@ async fn precharge(
         );
 
         if (10..=100).contains(chademo.soc()) {
-            if &old_soc != chademo.soc() {
-                old_soc = *chademo.soc();
+            old_soc = *chademo.soc();
+            let volts = chademo.precharge_volts(config);
+            if (volts - old_volts).abs() > 0.5 {
+                old_volts = volts;
                 log_error!(
-                    format!("SoC at {}", chademo.soc()),
-                    pre_tx
-                        .send(PreCommand::DcVoltsSetpoint(chademo.soc_to_voltage()))
-                        .await
+                    format!("SoC at {} target {volts:.1}V", chademo.soc()),
+                    pre_tx.send(PreCommand::DcVoltsSetpoint(volts)).await
                 );
             }
         }
@@ -481,10 +755,10 @@ async fn precharge(
             // dbg!(chademo.x102);
             // dbg!(chademo.x109);
             if chademo.x102.contactors_closed() {
-                if predata.volts_equal() {
+                if predata.volts_equal(config.tolerance) {
                     if chademo.x102.car_ready() {
                         // dbg!(&chademo);
-                        return chademo.close_contactors();
+                        return chademo.close_contactors(&APP_CONFIG.feedback).await;
                     } else {
                         log::warn!("x102.5.0 low");
                     }
@@ -506,13 +780,12 @@ async fn amps_meter_profiler(
     last_setpoint_amps: &f32,
     chademo: &Chademo,
 ) -> Result<f32, IndraError> {
-    let meter = if let Some(val) = *METER.read().await {
-        val + METER_BIAS
-    } else {
-        // return Err(IndraError::MeterOffline);
-        log::error!("Meter offline");
-        *feedback
+    let Some(reading) = *METER.read().await else {
+        // nothing to balance against, the amps slew ramps the Pre down
+        log::error!("Meter offline, ramping to 0A");
+        return Ok(0.0);
     };
+    let meter = reading.power + METER_BIAS;
 
     if *feedback == meter && meter.is_normal() {
         return Ok(*last_setpoint_amps);
@@ -525,23 +798,41 @@ async fn amps_meter_profiler(
 }
 
 fn calculate_setpoint_amps(last_setpoint_amps: &f32, meter: f32, chademo: &Chademo) -> f32 {
-    let setpoint_amps = last_setpoint_amps - (meter / chademo.x109.output_voltage) * 0.45;
+    let Some(volts) = profile_volts(chademo.x109.output_voltage, *chademo.target_voltage()) else {
+        log::warn!("No DC voltage for meter profile, holding {last_setpoint_amps}A");
+        return *last_setpoint_amps;
+    };
+    let setpoint_amps = last_setpoint_amps - (meter / volts) * 0.45;
     setpoint_amps.clamp(
         -1.0 * chademo.x200.maximum_discharge_current as f32,
         chademo.x102.charging_current_request as f32,
     )
 }
 
+/// Measured DC volts, the EV target while the Pre output is not yet live or unreported
+fn profile_volts(measured: f32, target: f32) -> Option<f32> {
+    [measured, target]
+        .into_iter()
+        .find(|volts| *volts >= PRE_MIN_VOLTS as f32)
+}
+
 fn limit_setpoint_amps(setpoint_amps: f32, chademo: &Chademo) -> f32 {
     let soc = *chademo.soc();
-    if matches!(chademo.state(), OperationMode::V2h) {
-        if MIN_SOC >= soc && setpoint_amps.is_sign_negative() {
-            warn!("SoC: {} too low, discharge disabled", soc);
-            0.0
-        } else if MAX_SOC <= soc && setpoint_amps.is_sign_positive() {
-            warn!("SoC: {} too high, charge disabled", soc);
-            0.0
-        } else if setpoint_amps.is_sign_positive()
+    let window = chademo.soc_window();
+    if !window.can_discharge(soc) && setpoint_amps.is_sign_negative() {
+        warn!(
+            "SoC: {} at minimum {}% ({:?}), discharge disabled",
+            soc, window.min, window.min_source
+        );
+        0.0
+    } else if !window.can_charge(soc) && setpoint_amps.is_sign_positive() {
+        warn!(
+            "SoC: {} at maximum {}% ({:?}), charge disabled",
+            soc, window.max, window.max_source
+        );
+        0.0
+    } else if matches!(chademo.state(), OperationMode::V2h) {
+        if setpoint_amps.is_sign_positive()
             && setpoint_amps > chademo.x102.charging_current_request as f32
         {
             warn!(
@@ -576,7 +867,8 @@ async fn update_panel_leds(led_tx: &LedTx, chademo: &Chademo) {
         }
         _ => {
             // Calculate amps as a percentage vs. max amps
-            let amps = (chademo.output_amps().abs() as u8).min(MAX_AMPS) as u32 * 100;
+            let amps =
+                (chademo.output_amps().abs() as u8).min(chademo.max_amps() as u8) as u32 * 100;
             let neg = chademo.output_amps().is_negative();
             log_error!(
                 "Update LED State",
@@ -596,6 +888,39 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn profile_volts_test() {
+        assert_eq!(profile_volts(372.5, 410.0), Some(372.5));
+        assert_eq!(profile_volts(0.0, 410.0), Some(410.0));
+        assert_eq!(profile_volts(3.0, 0.0), None);
+    }
+
+    #[test]
+    fn stop_ramp_test() {
+        let start = Instant::now();
+        let step = Duration::from_millis(100);
+        let mut amps = SlewLimiter::new(10.0, 20.0, 16.0);
+        let mut stop = StopRamp::default();
+        assert!(!stop.is_done(0.0, start));
+
+        stop.start(start);
+        // a later stop reason keeps the first start time
+        stop.start(start + step * 10);
+        let mut steps = 0;
+        while !stop.is_done(amps.output(), start + step * steps) {
+            amps.step(0.0, step);
+            steps += 1;
+        }
+        assert_eq!(amps.output(), 0.0);
+        assert_eq!(steps, 8);
+
+        // gives up on a ramp that never finishes
+        let mut stop = StopRamp::default();
+        stop.start(start);
+        assert!(!stop.is_done(16.0, start + STOP_RAMP_LIMIT));
+        assert!(stop.is_done(16.0, start + STOP_RAMP_LIMIT + step));
+    }
+
     #[test]
     fn test_x109() {
         // let mut chademo = Chademo::new();
@@ -1,3 +1,6 @@
 pub(crate) mod can;
+pub(crate) mod estimate;
 pub mod ev_connect;
+pub(crate) mod precharge;
+pub(crate) mod soc_window;
 pub(crate) mod state;
@@ -0,0 +1,188 @@
+use crate::data_io::config::{PrechargeConfig, VoltageSense};
+use chademo_v2::{X100, X102};
+use serde::Serialize;
+
+/// Used when no vehicle data or matching config curve is available
+const DEFAULT_CURVE: [(u8, f32); 2] = [(0, 330.0), (98, 394.0)];
+
+/// Where the precharge voltage target came from
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum TargetSource {
+    /// Contactor side voltage sense
+    Measured,
+    /// Per vehicle curve from config.toml
+    VehicleCurve,
+    /// Interpolated between x100 minimum and x102 target / x100 maximum
+    VehicleLimits,
+    Default,
+}
+
+/// Linear interpolation of a SoC -> volts curve, points sorted by SoC
+pub fn interpolate(curve: &[(u8, f32)], soc: u8) -> Option<f32> {
+    let first = curve.first()?;
+    let last = curve.last()?;
+    if soc <= first.0 {
+        return Some(first.1);
+    }
+    if soc >= last.0 {
+        return Some(last.1);
+    }
+    curve.windows(2).find_map(|w| {
+        let ((s0, v0), (s1, v1)) = (w[0], w[1]);
+        (s0..=s1).contains(&soc).then(|| {
+            let span = (s1 - s0).max(1) as f32;
+            v0 + (v1 - v0) * (soc - s0) as f32 / span
+        })
+    })
+}
+
+/// Contactor side volts, None if the sense is unreadable or reads near zero
+pub fn measured_volts(sense: &VoltageSense) -> Option<f32> {
+    let raw = match std::fs::read_to_string(&sense.path) {
+        Ok(raw) => raw,
+        Err(e) => {
+            log::warn!("Voltage sense {} {e}", sense.path);
+            return None;
+        }
+    };
+    let volts = raw.trim().parse::<f32>().ok()? * sense.scale + sense.offset;
+    (volts > 10.0).then_some(volts)
+}
+
+/// Precharge voltage target for the connected vehicle
+///
+/// Priority: measured contactor side volts, config vehicle curve, x100/x102 limits, default curve.
+/// Result is clamped to the vehicle's x100 minimum and x102 target / x100 maximum.
+pub fn target_volts(
+    x100: &X100,
+    x102: &X102,
+    config: &PrechargeConfig,
+    measured: Option<f32>,
+) -> (f32, TargetSource) {
+    let soc = x102.state_of_charge;
+    let min = x100.minimum_battery_voltage;
+    let max = match (x102.target_battery_voltage, x100.maximum_battery_voltage) {
+        (t, m) if t > 0.0 && m > 0.0 => t.min(m),
+        (t, m) => t.max(m),
+    };
+
+    let curve = config
+        .vehicles
+        .iter()
+        .find(|v| v.target_voltage as f32 == x102.target_battery_voltage)
+        .and_then(|v| interpolate(&v.curve, soc));
+
+    let (volts, source) = if let Some(volts) = measured {
+        (volts, TargetSource::Measured)
+    } else if let Some(volts) = curve {
+        (volts, TargetSource::VehicleCurve)
+    } else if min > 0.0 && max > min {
+        (
+            min + (max - min) * soc.min(100) as f32 / 100.0,
+            TargetSource::VehicleLimits,
+        )
+    } else {
+        (
+            interpolate(&DEFAULT_CURVE, soc).unwrap_or(DEFAULT_CURVE[0].1),
+            TargetSource::Default,
+        )
+    };
+
+    let volts = if max > 0.0 { volts.min(max) } else { volts };
+    (volts.max(min), source)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data_io::config::VehicleCurve;
+    use tokio_socketcan::CANFrame;
+
+    fn frames() -> (X100, X102) {
+        let x100 = X100::from(
+            &CANFrame::new(
+                0x100,
+                &[0, 0, 0x2c, 0x01, 0xae, 0x01, 0x64, 0],
+                false,
+                false,
+            )
+            .unwrap(),
+        ); // min 300V, max 430V
+        let x102 = X102::from(
+            &CANFrame::new(
+                0x102,
+                &[0x2, 0x9A, 0x1, 0x0E, 0x0, 0xC1, 0x32, 0x0],
+                false,
+                false,
+            )
+            .unwrap(),
+        ); // target 410V, SoC 50%
+        (x100, x102)
+    }
+
+    #[test]
+    fn interpolate_test() {
+        let curve = [(0, 330.0), (50, 370.0), (100, 400.0)];
+        assert_eq!(interpolate(&curve, 0), Some(330.0));
+        assert_eq!(interpolate(&curve, 25), Some(350.0));
+        assert_eq!(interpolate(&curve, 50), Some(370.0));
+        assert_eq!(interpolate(&curve, 75), Some(385.0));
+        assert_eq!(interpolate(&curve, 100), Some(400.0));
+        assert_eq!(interpolate(&[], 50), None);
+        assert_eq!(interpolate(&[(10, 350.0)], 50), Some(350.0));
+    }
+
+    #[test]
+    fn target_volts_test() {
+        let (x100, x102) = frames();
+        let mut config = PrechargeConfig::default();
+
+        // vehicle limits, 300V + (410V - 300V) * 50%
+        assert_eq!(
+            target_volts(&x100, &x102, &config, None),
+            (355.0, TargetSource::VehicleLimits)
+        );
+
+        // measured volts take priority, still clamped to vehicle range
+        assert_eq!(
+            target_volts(&x100, &x102, &config, Some(362.0)),
+            (362.0, TargetSource::Measured)
+        );
+        assert_eq!(
+            target_volts(&x100, &x102, &config, Some(450.0)),
+            (410.0, TargetSource::Measured)
+        );
+
+        // config curve for a matching vehicle
+        config.vehicles.push(VehicleCurve {
+            name: "test".into(),
+            target_voltage: 410,
+            curve: vec![(0, 340.0), (100, 400.0)],
+        });
+        assert_eq!(
+            target_volts(&x100, &x102, &config, None),
+            (370.0, TargetSource::VehicleCurve)
+        );
+
+        // nothing known about the vehicle
+        let (volts, source) = target_volts(&X100::default(), &X102::default(), &config, None);
+        assert_eq!((volts, source), (330.0, TargetSource::Default));
+    }
+
+    #[test]
+    fn measured_volts_test() {
+        let path = std::env::temp_dir().join("indra_in_voltage0_raw");
+        std::fs::write(&path, "1810\n").unwrap();
+        let mut sense = VoltageSense {
+            path: path.to_string_lossy().into(),
+            scale: 0.2,
+            offset: 0.0,
+        };
+        assert_eq!(measured_volts(&sense), Some(362.0));
+        // dead bus
+        sense.scale = 0.001;
+        assert_eq!(measured_volts(&sense), None);
+        sense.path = "/nonexistent/in_voltage0_raw".into();
+        assert_eq!(measured_volts(&sense), None);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{data_io::config::SocConfig, global_state::OperationMode};
+use serde::Serialize;
+
+/// Party that imposed the active SoC limit
+#[derive(Serialize, Default, Debug, Clone, Copy, PartialEq)]
+pub enum SocLimiter {
+    #[default]
+    None,
+    /// x200 minimum discharge level / maximum charging capacity
+    Vehicle,
+    /// config.toml [soc], V2H & eco charging only
+    Config,
+    /// ChargeParameters soc_limit
+    Mode,
+}
+
+/// Effective SoC window, intersection of vehicle, config and mode limits
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct SocWindow {
+    pub min: u8,
+    pub min_source: SocLimiter,
+    pub max: u8,
+    pub max_source: SocLimiter,
+}
+
+impl Default for SocWindow {
+    fn default() -> Self {
+        Self {
+            min: 0,
+            min_source: SocLimiter::None,
+            max: 100,
+            max_source: SocLimiter::None,
+        }
+    }
+}
+
+impl SocWindow {
+    /// vehicle_min/vehicle_max are x200 values, 0 or >100 is unset
+    pub fn new(vehicle_min: u8, vehicle_max: u8, config: &SocConfig, mode: &OperationMode) -> Self {
+        let mut window = Self::default();
+        if (1..=100).contains(&vehicle_min) {
+            window.restrict_min(vehicle_min, SocLimiter::Vehicle);
+        }
+        if (1..=100).contains(&vehicle_max) {
+            window.restrict_max(vehicle_max, SocLimiter::Vehicle);
+        }
+        // manual charge & discharge keep to the vehicle & mode limits
+        let unattended = match mode {
+            OperationMode::V2h => true,
+            OperationMode::Charge(cp) => cp.get_eco(),
+            _ => false,
+        };
+        if unattended {
+            window.restrict_min(config.min, SocLimiter::Config);
+            window.restrict_max(config.max, SocLimiter::Config);
+        }
+        match mode {
+            OperationMode::Charge(cp) => {
+                if let Some(limit) = cp.get_soc_limit() {
+                    window.restrict_max(limit, SocLimiter::Mode)
+                }
+            }
+            OperationMode::Discharge(cp) => {
+                if let Some(limit) = cp.get_soc_limit() {
+                    window.restrict_min(limit, SocLimiter::Mode)
+                }
+            }
+            _ => (),
+        }
+        window
+    }
+    fn restrict_min(&mut self, min: u8, source: SocLimiter) {
+        if min > self.min {
+            self.min = min;
+            self.min_source = source;
+        }
+    }
+    fn restrict_max(&mut self, max: u8, source: SocLimiter) {
+        if max < self.max {
+            self.max = max;
+            self.max_source = source;
+        }
+    }
+    pub fn can_charge(&self, soc: u8) -> bool {
+        soc < self.max
+    }
+    pub fn can_discharge(&self, soc: u8) -> bool {
+        soc > self.min
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::global_state::ChargeParameters;
+
+    #[test]
+    fn soc_window_test() {
+        let config = SocConfig { min: 31, max: 90 };
+
+        // config only
+        let window = SocWindow::new(0, 0, &config, &OperationMode::V2h);
+        assert_eq!((window.min, window.min_source), (31, SocLimiter::Config));
+        assert_eq!((window.max, window.max_source), (90, SocLimiter::Config));
+        assert!(!window.can_discharge(31));
+        assert!(window.can_discharge(32));
+        assert!(!window.can_charge(90));
+        assert!(window.can_charge(89));
+
+        // vehicle narrower than config
+        let window = SocWindow::new(40, 80, &config, &OperationMode::V2h);
+        assert_eq!((window.min, window.min_source), (40, SocLimiter::Vehicle));
+        assert_eq!((window.max, window.max_source), (80, SocLimiter::Vehicle));
+
+        // unset vehicle values are ignored
+        let window = SocWindow::new(255, 0, &config, &OperationMode::V2h);
+        assert_eq!(window.min_source, SocLimiter::Config);
+        assert_eq!(window.max_source, SocLimiter::Config);
+
+        // charge mode limit is a maximum
+        let mode = OperationMode::Charge(ChargeParameters::default().set_soc_limit(70));
+        let window = SocWindow::new(40, 80, &config, &mode);
+        assert_eq!((window.max, window.max_source), (70, SocLimiter::Mode));
+        assert_eq!(window.min_source, SocLimiter::Vehicle);
+
+        // discharge mode limit is a minimum
+        let mode = OperationMode::Discharge(ChargeParameters::default().set_soc_limit(50));
+        let window = SocWindow::new(40, 80, &config, &mode);
+        assert_eq!((window.min, window.min_source), (50, SocLimiter::Mode));
+        assert_eq!(window.max_source, SocLimiter::Vehicle);
+
+        // mode cannot widen the window
+        let mode =
+            OperationMode::Charge(ChargeParameters::default().set_soc_limit(100).set_eco(true));
+        let window = SocWindow::new(0, 0, &config, &mode);
+        assert_eq!((window.max, window.max_source), (90, SocLimiter::Config));
+
+        // config doesn't apply to manual charge
+        let mode = OperationMode::Charge(ChargeParameters::default());
+        let window = SocWindow::new(0, 0, &config, &mode);
+        assert_eq!((window.max, window.max_source), (100, SocLimiter::None));
+        assert_eq!((window.min, window.min_source), (0, SocLimiter::None));
+    }
+}
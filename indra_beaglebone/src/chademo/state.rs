@@ -1,4 +1,19 @@
-use crate::{error::IndraError, global_state::OperationMode, MAX_AMPS};
+use super::{
+    estimate::{minutes_to_target, TimeEstimator},
+    precharge,
+    soc_window::SocWindow,
+};
+use crate::{
+    data_io::{
+        config::{FeedbackConfig, PrechargeConfig, SocConfig},
+        wear::{self, Device},
+    },
+    error::IndraError,
+    global_state::OperationMode,
+    pre_charger::derate::ThermalDerate,
+    safety::{estop, feedback},
+    MAX_AMPS,
+};
 use chademo_v2::*;
 use lazy_static::lazy_static;
 use log::warn;
@@ -24,6 +39,12 @@ pub const PLUG_LOCK: u64 = PinVal::GPIO_P8_16 as u64; // Solenoid in CHAdeMO plu
 pub(crate) const MASTERCONTACTOR: u64 = PinVal::GPIO_P8_12 as u64; // lockout
 pub(crate) const PREACPIN: u64 = PinVal::GPIO_P8_28 as u64; // AC contactor in charger
 
+/// Pre DC output voltage range
+pub(crate) const PRE_MAX_VOLTS: u16 = 500;
+pub(crate) const PRE_MIN_VOLTS: u16 = 250;
+/// x208 lower threshold margin below vehicle minimum discharge voltage
+const LOWER_THRESHOLD_MARGIN: u16 = 10;
+
 #[derive(Copy, Clone, Debug)]
 pub struct Pins {
     pub d1: Pin,
@@ -69,6 +90,12 @@ pub struct Chademo {
     pub x209: X209,
     state: OperationMode,
     amps: i16,
+    soc_window: SocWindow,
+    estimator: TimeEstimator,
+    time_to_target: Option<u16>,
+    derate: ThermalDerate,
+    /// Combined output of the Pre modules in use
+    max_amps: f32,
 }
 
 impl std::fmt::Display for Chademo {
@@ -106,11 +133,16 @@ impl Chademo {
             x200: X200::default(),
             //EVSE encode
             x109: X109::new(2, true),
-            x108: X108::new(MAX_AMPS, 500, true, 435).into(),
-            x208: X208::new(0, 500, MAX_AMPS, 250),
+            x108: X108::new(MAX_AMPS, PRE_MAX_VOLTS, true, 435).into(),
+            x208: X208::new(0, 500, MAX_AMPS, PRE_MIN_VOLTS),
             x209: X209::new(2, 0),
             state: OperationMode::Uninitalised,
             amps: 0,
+            soc_window: SocWindow::default(),
+            estimator: TimeEstimator::default(),
+            time_to_target: None,
+            derate: ThermalDerate::default(),
+            max_amps: MAX_AMPS as f32,
         }
     }
     /// Flag to EV that charge has been cancelled
@@ -122,10 +154,12 @@ impl Chademo {
     /// Reports Pre current to EV, both charge and discharge
     pub fn update_amps(&mut self, amps: impl Into<i16>) {
         self.amps = amps.into();
-        (self.x208.discharge_current, self.x109.output_current) = match self.amps.is_negative() {
-            true => (self.amps.abs() as u8, 0),
-            false => (0, self.amps as u8),
-        };
+        (self.x208.discharge_current, self.x109.output_current) = split_amps(self.amps);
+    }
+    /// Reports measured Pre output (volts, signed amps) to EV via x109/x208
+    pub fn update_measured(&mut self, volts: f32, amps: f32) {
+        self.x109.output_voltage = volts.max(0.0);
+        self.update_amps(amps.round() as i16);
     }
 
     pub fn x102_status(&self) -> X102Status {
@@ -148,14 +182,12 @@ impl Chademo {
         ]
     }
 
-    pub fn soc_to_voltage(&mut self) -> f32 {
-        // assert!(self.soc <= 100, "soc > 100%");
-        let min_input = 0;
-        let max_input = 98;
-        let min_output = 330.0;
-        let max_output = 394.0;
-        let normalized_input = f32::from(self.soc() - min_input) / f32::from(max_input - min_input);
-        min_output + (max_output - min_output) * normalized_input
+    /// Precharge voltage target, contactor side volts if sensed else from vehicle data
+    pub fn precharge_volts(&self, config: &PrechargeConfig) -> f32 {
+        let measured = config.sense.as_ref().and_then(precharge::measured_volts);
+        let (volts, source) = precharge::target_volts(&self.x100, &self.x102, config, measured);
+        log::debug!("Precharge target {volts:.1}V from {source:?}");
+        volts
     }
 
     pub fn update_dynamic_charge_limits(&mut self, amps: impl Into<f32>) {
@@ -166,8 +198,37 @@ impl Chademo {
         }
     }
     pub fn disable_dynamic_charge_limits(&mut self) {
-        self.set_max_discharge_amps(MAX_AMPS);
-        self.set_max_charge_amps(MAX_AMPS);
+        self.set_max_discharge_amps(self.max_amps as u8);
+        self.set_max_charge_amps(self.max_amps as u8);
+    }
+    /// Current the Pre modules in use can supply, reported to EV before derating
+    pub fn set_max_amps(&mut self, amps: f32) {
+        if amps != self.max_amps {
+            log::info!("Pre output limit {amps:.0}A");
+            self.max_amps = amps;
+        }
+        self.apply_derate(self.derate);
+    }
+    pub fn max_amps(&self) -> f32 {
+        self.max_amps
+    }
+
+    /// Reports derated current available to EV via x108 & x208
+    pub fn apply_derate(&mut self, derate: ThermalDerate) {
+        if derate.state != self.derate.state {
+            log::warn!(
+                "Thermal derate {:?} {:.0}%",
+                derate.state,
+                derate.factor * 100.0
+            );
+        }
+        self.derate = derate;
+        let amps = derate.limit(self.max_amps) as u8;
+        self.x108.available_output_current = amps;
+        self.x208.set_input_current(amps);
+    }
+    pub fn derate(&self) -> &ThermalDerate {
+        &self.derate
     }
 
     pub fn output_volts(&self) -> &f32 {
@@ -190,6 +251,60 @@ impl Chademo {
     pub fn state(&self) -> &OperationMode {
         &self.state
     }
+    pub fn soc_window(&self) -> &SocWindow {
+        &self.soc_window
+    }
+    /// Minutes until the active SoC target is reached
+    pub fn time_to_target(&self) -> Option<u16> {
+        self.time_to_target
+    }
+    /// Estimates remaining time from x101 capacity, SoC window and measured power
+    ///
+    /// Charging fills x109 remaining time, discharging fills x209
+    pub fn update_time_to_target(&mut self) {
+        let power = self
+            .estimator
+            .update(self.amps as f32 * self.x109.output_voltage);
+        let discharging = power.is_sign_negative();
+        let target = match discharging {
+            true => self.soc_window.min,
+            false => self.soc_window.max,
+        };
+        self.time_to_target = minutes_to_target(
+            self.x101.rated_battery_capacity_kwh(),
+            *self.soc(),
+            target,
+            power,
+        );
+        match (self.time_to_target, discharging) {
+            (Some(minutes), false) => {
+                self.x109.remaining_charging_time_10s_bit = 0xff;
+                self.x109.remaining_charging_time_1min_bit = minutes.min(0xfe) as u8;
+                self.x209.remaing_discharge_time = 0;
+            }
+            (Some(minutes), true) => self.x209.remaing_discharge_time = minutes,
+            (None, _) => (),
+        }
+    }
+    /// Recalculates the SoC window from x200, config and current mode
+    pub fn update_soc_window(&mut self, config: &SocConfig) {
+        let window = SocWindow::new(
+            self.x200.minimum_battery_discharge_level,
+            self.x200.max_remaining_capacity_for_charging,
+            config,
+            &self.state,
+        );
+        if window != self.soc_window {
+            log::info!(
+                "SoC window {}% ({:?}) - {}% ({:?})",
+                window.min,
+                window.min_source,
+                window.max,
+                window.max_source
+            );
+            self.soc_window = window;
+        }
+    }
 
     pub fn set_state(&mut self, state: OperationMode) {
         self.state = state;
@@ -233,6 +348,24 @@ impl Chademo {
             == 0
             && self.x102.can_close_contactors())
     }
+    /// Sets x108 threshold & x208 lower threshold from received vehicle limits
+    ///
+    /// Flags 109.5.3 if the battery is outside what the Pre can do
+    pub fn negotiate_limits(&mut self) -> Result<(), IndraError> {
+        match negotiate_thresholds(&self.x100, &self.x102, &self.x200) {
+            Ok((threshold, lower_threshold)) => {
+                log::info!("Negotiated x108 threshold {threshold}V, x208 lower threshold {lower_threshold}V");
+                self.x108.threshold_voltage = threshold;
+                self.x208.set_lower_threshold_voltage(lower_threshold);
+                self.x109.status.fault_battery_incompatibility = false;
+                Ok(())
+            }
+            Err(e) => {
+                self.x109.status.fault_battery_incompatibility = true;
+                Err(e)
+            }
+        }
+    }
     pub fn charge_start(&mut self) {
         self.x109.status.status_charger_stop_control = false;
         self.x109.status.status_station = true;
@@ -240,6 +373,9 @@ impl Chademo {
         // self.plug_lock(true);
         self.x109.remaining_charging_time_10s_bit = 255;
         self.x109.remaining_charging_time_1min_bit = 60;
+        self.x209.remaing_discharge_time = 0;
+        self.estimator.reset();
+        self.time_to_target = None;
     }
     pub fn charge_stop(&mut self) {
         // self.status_charger_stop_control(true);
@@ -260,11 +396,18 @@ impl Chademo {
     // pub fn status_station_enabled(&mut self, state: bool) {
     //     self.x109.status.status_station = state;
     // }
-    pub fn plug_lock(&mut self, state: bool) -> Result<(), IndraError> {
-        self.pins
-            .pluglock
-            .set_value(state.into())
+    /// Drives the plug lock, 109.5.2 only follows once feedback confirms it
+    pub async fn plug_lock(
+        &mut self,
+        state: bool,
+        config: &FeedbackConfig,
+    ) -> Result<(), IndraError> {
+        wear::switch(Device::PlugLock, &self.pins.pluglock, state.into(), 0.0)
             .map_err(|e| IndraError::PinAccess(e))?;
+        if let Err(e) = feedback::verify(Device::PlugLock, state, config).await {
+            self.x109.status.fault_station_malfunction = true;
+            return Err(e);
+        }
         self.x109.status.status_vehicle_connector_lock = state; // unsure
         Ok(())
     }
@@ -274,22 +417,33 @@ impl Chademo {
     pub fn status_vehicle_ok(&self) -> bool {
         !self.x102.status.status_vehicle
     }
+    /// Checks c1 & c2 feedback, flags 109.5.1 station malfunction on failure
+    pub async fn verify_contactors(
+        &mut self,
+        closed: bool,
+        config: &FeedbackConfig,
+    ) -> Result<(), IndraError> {
+        for device in [Device::C1, Device::C2] {
+            if let Err(e) = feedback::verify(device, closed, config).await {
+                self.x109.status.fault_station_malfunction = true;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
     pub fn charging_stop_control_set(&mut self) {
         self.x109.status.status_charger_stop_control = false
     }
     pub fn charging_stop_control_release(&mut self) {
         self.x109.status.status_charger_stop_control = true
     }
-    pub fn close_contactors(&mut self) -> Result<(), IndraError> {
+    pub async fn close_contactors(&mut self, config: &FeedbackConfig) -> Result<(), IndraError> {
+        // open_all may have run since the session started
+        estop::ensure_clear().await?;
         log::info!("Contactors closing");
-        self.pins()
-            .c1
-            .set_value(1)
-            .map_err(|e| IndraError::PinAccess(e))?;
-        self.pins()
-            .c2
-            .set_value(1)
-            .map_err(|e| IndraError::PinAccess(e))?;
+        wear::switch(Device::C1, &self.pins().c1, 1, 0.0).map_err(|e| IndraError::PinAccess(e))?;
+        wear::switch(Device::C2, &self.pins().c2, 1, 0.0).map_err(|e| IndraError::PinAccess(e))?;
+        self.verify_contactors(true, config).await?;
 
         print!("\x07");
         //109.5.5
@@ -298,6 +452,38 @@ impl Chademo {
     }
 }
 
+/// Returns (x108 threshold, x208 lower threshold) volts
+///
+/// Threshold is the lower of Pre available voltage and x100 maximum battery voltage,
+/// lower threshold sits just under x200 minimum discharge voltage.
+fn negotiate_thresholds(x100: &X100, x102: &X102, x200: &X200) -> Result<(u16, u16), IndraError> {
+    let target = x102.target_battery_voltage as u16;
+    let minimum = x100.minimum_battery_voltage as u16;
+    if target > PRE_MAX_VOLTS || (minimum != 0 && minimum < PRE_MIN_VOLTS) {
+        return Err(IndraError::BatteryIncompatible(target, minimum));
+    }
+    let threshold = match x100.maximum_battery_voltage as u16 {
+        0 => PRE_MAX_VOLTS,
+        max => max.min(PRE_MAX_VOLTS),
+    };
+    let lower_threshold = match x200.minimum_discharge_voltage {
+        0 => PRE_MIN_VOLTS,
+        min => min
+            .saturating_sub(LOWER_THRESHOLD_MARGIN)
+            .max(PRE_MIN_VOLTS),
+    };
+    Ok((threshold, lower_threshold))
+}
+
+/// Splits signed amps into (x208 discharge, x109 output), negative is discharge
+fn split_amps(amps: i16) -> (u8, u8) {
+    let abs = amps.unsigned_abs().min(u8::MAX as u16) as u8;
+    match amps.is_negative() {
+        true => (abs, 0),
+        false => (0, abs),
+    }
+}
+
 pub fn pin_init_out_low(pin: u64) -> Result<Pin, IndraError> {
     let pin_out_low = Pin::new(pin);
     pin_out_low
@@ -499,4 +685,76 @@ mod test {
         assert!(y.get_input_current() == 16);
         assert!(y.get_lower_threshold_voltage() == 250);
     }
+    #[test]
+    fn negotiate_thresholds_test() {
+        let x100 = X100::from(
+            &CANFrame::new(
+                0x100,
+                &[0, 0, 0x2c, 0x01, 0xae, 0x01, 0x64, 0],
+                false,
+                false,
+            )
+            .unwrap(),
+        ); // min 300V, max 430V
+        let x102 = X102::from(
+            &CANFrame::new(
+                0x102,
+                &[0x2, 0x9A, 0x1, 0x0E, 0x0, 0xC1, 0x56, 0x0],
+                false,
+                false,
+            )
+            .unwrap(),
+        ); // target 410V
+        let x200 = X200::from(
+            &CANFrame::new(
+                0x200,
+                &[0xef, 0, 0, 0, 0x36, 0x01, 0xe1, 0x5a],
+                false,
+                false,
+            )
+            .unwrap(),
+        ); // min discharge 310V
+        assert_eq!(
+            negotiate_thresholds(&x100, &x102, &x200).unwrap(),
+            (430, 300)
+        );
+
+        // unset vehicle limits fall back to Pre range
+        let unset = X200::default();
+        assert_eq!(
+            negotiate_thresholds(&X100::default(), &x102, &unset).unwrap(),
+            (PRE_MAX_VOLTS, PRE_MIN_VOLTS)
+        );
+
+        // target above Pre maximum
+        let mut high = x102;
+        high.target_battery_voltage = 510.0;
+        assert!(negotiate_thresholds(&x100, &high, &x200).is_err());
+
+        // vehicle minimum below Pre minimum
+        let mut low = x100;
+        low.minimum_battery_voltage = 200.0;
+        assert!(negotiate_thresholds(&low, &x102, &x200).is_err());
+    }
+    #[test]
+    fn split_amps_test() {
+        // charging EV
+        assert_eq!(split_amps(12), (0, 12));
+        // discharging EV
+        assert_eq!(split_amps(-12), (12, 0));
+        assert_eq!(split_amps(0), (0, 0));
+        // saturates rather than wrapping
+        assert_eq!(split_amps(300), (0, 255));
+        assert_eq!(split_amps(-300), (255, 0));
+        assert_eq!(split_amps(i16::MIN), (255, 0));
+
+        let mut x208 = X208::new(0, 500, 16, 250);
+        let mut x109 = X109::new(2, true);
+        (x208.discharge_current, x109.output_current) = split_amps(-7);
+        assert!(x208.to_can().data()[0] == 0xff - 7);
+        assert!(x109.to_can().data()[3] == 0);
+        (x208.discharge_current, x109.output_current) = split_amps(9);
+        assert!(x208.to_can().data()[0] == 0xff);
+        assert!(x109.to_can().data()[3] == 9);
+    }
 }
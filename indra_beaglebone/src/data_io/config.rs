@@ -1,16 +1,31 @@
+use super::{
+    modbus::{Function, Transport},
+    wear::Device,
+};
+use crate::{
+    pre_charger::{
+        sdo::PRE_NODE,
+        status::{FaultAction, PreStatus},
+        Register,
+    },
+    MAX_AMPS, MAX_SOC, MIN_SOC,
+};
 use serde::Deserialize;
-use std::sync::Arc;
 use std::{fs, panic};
+use std::{sync::Arc, time::Duration};
 
 lazy_static::lazy_static! {
     pub static ref APP_CONFIG: Arc<AppConfig> = {
         let config_file = "config.toml";
         let toml_str = fs::read_to_string(config_file)
             .expect(&format!("Failed to read configuration file: {}", config_file));
-        let config = match toml::from_str(&toml_str) {
+        let config: AppConfig = match toml::from_str(&toml_str) {
             Ok(t) => t,
             Err(e) => panic!("TOML parse fail {e:?}"),
         };
+        if let Err(e) = config.validate() {
+            panic!("Invalid configuration, {e}");
+        }
         Arc::new(config)
     };
 }
@@ -28,13 +43,616 @@ pub struct MqttConfig {
     pub sub: String,
 }
 
+/// SDM230 or compatible, via a serial gateway, native Modbus TCP or RS-485
 #[derive(Debug, Deserialize, Clone)]
 pub struct MeterConfig {
+    #[serde(default)]
+    pub source: MeterSource,
+    /// TCP transports only
+    #[serde(default)]
     pub address: String,
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default)]
+    pub serial: SerialConfig,
+    #[serde(default)]
+    pub profile: MeterProfile,
+    /// User register map, overrides the profile
+    pub registers: Option<RegisterMap>,
+    #[serde(default)]
+    pub mqtt: MqttMeterConfig,
+    #[serde(default = "MeterConfig::default_slave_id")]
+    pub slave_id: u8,
+    /// Response timeout per request
+    #[serde(default = "MeterConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "MeterConfig::default_period_ms")]
+    pub period_ms: u64,
+}
+
+impl MeterConfig {
+    fn default_slave_id() -> u8 {
+        1
+    }
+    fn default_timeout_ms() -> u64 {
+        400
+    }
+    fn default_period_ms() -> u64 {
+        500
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MeterSource {
+    #[default]
+    Modbus,
+    /// Grid power published by another system
+    Mqtt,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MqttMeterConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topic: String,
+    /// JSON pointer to the value, payload is a bare number if unset
+    pub pointer: Option<String>,
+    /// To W, e.g. 1000 for kW
+    pub scale: f32,
+    /// Source publishes export as positive
+    pub invert: bool,
+    /// Meter offline after this long without a value
+    pub stale_ms: u64,
+}
+
+impl Default for MqttMeterConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "indra-meter".to_string(),
+            username: None,
+            password: None,
+            topic: String::new(),
+            pointer: None,
+            scale: 1.0,
+            invert: false,
+            stale_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MeterProfile {
+    Sdm120,
+    #[default]
+    Sdm230,
+    /// Three phase, totals across phases
+    Sdm630,
+    Sdm72,
+}
+
+/// Start addresses of IEEE 754 float register pairs
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct RegisterMap {
+    #[serde(default = "RegisterMap::default_function")]
+    pub function: Function,
+    /// W, positive importing
+    pub power: u16,
+    pub volts: Option<u16>,
+    pub hz: Option<u16>,
+    pub amps: Option<u16>,
+    pub import_kwh: Option<u16>,
+    pub export_kwh: Option<u16>,
+}
+
+impl RegisterMap {
+    fn default_function() -> Function {
+        Function::ReadInput
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// UART wired to the meter RS-485 bus
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SerialConfig {
+    pub path: String,
+    pub baud: u32,
+    pub parity: Parity,
+    /// 1 or 2
+    pub stop_bits: u8,
+    /// Silence between frames, 3.5 characters if unset
+    pub inter_frame_ms: Option<u64>,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            path: "/dev/ttyS1".to_string(),
+            baud: 9600,
+            parity: Parity::None,
+            stop_bits: 1,
+            inter_frame_ms: None,
+        }
+    }
+}
+
+impl SerialConfig {
+    pub fn inter_frame(&self) -> Duration {
+        match self.inter_frame_ms {
+            Some(ms) => Duration::from_millis(ms),
+            // fixed above 19200 baud
+            None if self.baud > 19200 => Duration::from_micros(1750),
+            // 11 bit characters
+            None => Duration::from_micros(38_500_000 / self.baud.max(1) as u64),
+        }
+    }
+}
+
+/// User SoC window, applies in every mode
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct SocConfig {
+    pub min: u8,
+    pub max: u8,
+}
+
+impl Default for SocConfig {
+    fn default() -> Self {
+        Self {
+            min: MIN_SOC,
+            max: MAX_SOC,
+        }
+    }
+}
+
+/// SoC -> volts curve for a vehicle, matched on x102 target battery voltage
+#[derive(Debug, Deserialize, Clone)]
+pub struct VehicleCurve {
+    pub name: String,
+    pub target_voltage: u16,
+    /// (SoC %, volts) points sorted by SoC
+    pub curve: Vec<(u8, f32)>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PrechargeConfig {
+    /// Pre output vs setpoint match, volts either side
+    pub tolerance: f32,
+    /// Pre self test voltage, vehicle data is not available before D1
+    pub init_volts: f32,
+    pub vehicles: Vec<VehicleCurve>,
+    /// Contactor side volts, preferred over vehicle data when readable
+    pub sense: Option<VoltageSense>,
+}
+
+impl Default for PrechargeConfig {
+    fn default() -> Self {
+        Self {
+            tolerance: 2.0,
+            init_volts: 370.0,
+            vehicles: Vec::new(),
+            sense: None,
+        }
+    }
+}
+
+/// Sysfs ADC channel, volts = raw * scale + offset
+#[derive(Debug, Deserialize, Clone)]
+pub struct VoltageSense {
+    /// e.g. /sys/bus/iio/devices/iio:device0/in_voltage0_raw
+    pub path: String,
+    pub scale: f32,
+    #[serde(default)]
+    pub offset: f32,
+}
+
+/// E-stop input, gpio number
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct EstopConfig {
+    pub pin: Option<u64>,
+    pub active_low: bool,
+}
+
+impl Default for EstopConfig {
+    fn default() -> Self {
+        Self {
+            pin: None,
+            active_low: true,
+        }
+    }
+}
+
+/// Auxiliary contact or voltage sense input, gpio number
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct FeedbackInput {
+    pub pin: u64,
+    /// Input reads 0 when the actuator is closed / locked
+    #[serde(default)]
+    pub active_low: bool,
+}
+
+/// Optional actuator feedback, unfitted inputs are not verified
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct FeedbackConfig {
+    pub timeout_ms: u64,
+    pub c1: Option<FeedbackInput>,
+    pub c2: Option<FeedbackInput>,
+    pub pre_ac: Option<FeedbackInput>,
+    pub plug_lock: Option<FeedbackInput>,
+}
+
+impl Default for FeedbackConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 500,
+            c1: None,
+            c2: None,
+            pre_ac: None,
+            plug_lock: None,
+        }
+    }
+}
+
+impl FeedbackConfig {
+    pub fn input(&self, device: Device) -> Option<&FeedbackInput> {
+        match device {
+            Device::C1 => self.c1.as_ref(),
+            Device::C2 => self.c2.as_ref(),
+            Device::PreAc => self.pre_ac.as_ref(),
+            Device::PlugLock => self.plug_lock.as_ref(),
+        }
+    }
+}
+
+/// Maintenance thresholds, applied to each contactor and the plug lock
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct WearConfig {
+    pub max_cycles: u32,
+    /// Opened with current flowing
+    pub max_load_switches: u32,
+    pub max_on_hours: f32,
+}
+
+impl Default for WearConfig {
+    fn default() -> Self {
+        Self {
+            max_cycles: 50_000,
+            max_load_switches: 10,
+            max_on_hours: 20_000.0,
+        }
+    }
+}
+
+/// Pre comms loss handling
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PreSupervisorConfig {
+    /// Consecutive unanswered reads of any register before the Pre is offline
+    pub max_misses: u8,
+    /// Cycle Pre AC & reinitialise after a loss, never while energised
+    pub power_cycle: bool,
+    pub off_secs: u64,
+    pub max_restarts: u8,
+}
+
+impl Default for PreSupervisorConfig {
+    fn default() -> Self {
+        Self {
+            max_misses: 5,
+            power_cycle: false,
+            off_secs: 5,
+            max_restarts: 2,
+        }
+    }
+}
+
+/// Pre power modules in parallel on can0, current is shared equally
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PreModulesConfig {
+    /// CANopen node IDs, SDO requests go to 0x600 + node
+    pub nodes: Vec<u8>,
+    /// Rated output of one module, the EV is offered this per module in use
+    pub module_amps: f32,
+}
+
+impl Default for PreModulesConfig {
+    fn default() -> Self {
+        Self {
+            nodes: vec![PRE_NODE],
+            module_amps: MAX_AMPS as f32,
+        }
+    }
+}
+
+impl PreModulesConfig {
+    /// Combined output with every configured module sharing
+    pub fn max_amps(&self) -> f32 {
+        self.module_amps * self.nodes.len() as f32
+    }
+}
+
+/// Pre 0x2101 status word meanings, bits not listed are logged only
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PreStatusConfig {
+    pub flags: Vec<StatusFlag>,
+}
+
+impl Default for PreStatusConfig {
+    fn default() -> Self {
+        Self {
+            flags: PreStatus::default_flags(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StatusFlag {
+    /// 0 is the least significant bit
+    pub bit: u8,
+    pub name: String,
+    /// "Derate", "Retry" for 30 s then stop, or "Stop" the session, omitted is logged only
+    #[serde(default)]
+    pub action: Option<FaultAction>,
+}
+
+/// Pre register poll periods in ms, 0 stops polling that group
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PrePollConfig {
+    /// DC output volts & amps
+    pub dc_output_ms: u64,
+    pub dc_bus_ms: u64,
+    /// Status word & enabled
+    pub status_ms: u64,
+    /// Volts & amps setpoint readback
+    pub setpoint_ms: u64,
+    pub temp_ms: u64,
+    /// AC volts & amps
+    pub ac_ms: u64,
+    pub ping_ms: u64,
+    pub timeout_ms: u64,
+    /// Requests awaiting an answer at once
+    pub max_in_flight: usize,
+}
+
+impl Default for PrePollConfig {
+    fn default() -> Self {
+        Self {
+            dc_output_ms: 100,
+            dc_bus_ms: 200,
+            status_ms: 200,
+            setpoint_ms: 500,
+            temp_ms: 1000,
+            ac_ms: 1000,
+            ping_ms: 1000,
+            timeout_ms: 100,
+            max_in_flight: 4,
+        }
+    }
+}
+
+impl PrePollConfig {
+    pub fn period(&self, register: Register) -> Option<Duration> {
+        let ms = match register {
+            Register::DcOutputV | Register::DcOutputA => self.dc_output_ms,
+            Register::DcBusV => self.dc_bus_ms,
+            Register::Status | Register::Enabled => self.status_ms,
+            Register::DcBusMaxVsetpoint | Register::DcBusMaxAsetpoint => self.setpoint_ms,
+            Register::Temp => self.temp_ms,
+            Register::AcV | Register::AcA => self.ac_ms,
+            Register::Ping => self.ping_ms,
+            _ => 0,
+        };
+        (ms > 0).then(|| Duration::from_millis(ms))
+    }
+}
+
+/// Pre setpoint ramps per second, amps rise & fall are by magnitude
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SlewConfig {
+    pub amps_rise: f32,
+    pub amps_fall: f32,
+    pub volts_rate: f32,
+}
+
+impl Default for SlewConfig {
+    fn default() -> Self {
+        Self {
+            amps_rise: 10.0,
+            amps_fall: 20.0,
+            volts_rate: 20.0,
+        }
+    }
+}
+
+/// Pre temperature current derating, °C
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DerateConfig {
+    pub full_below: f32,
+    /// No current at or above, linear between
+    pub stop_above: f32,
+}
+
+impl Default for DerateConfig {
+    fn default() -> Self {
+        Self {
+            full_below: 65.0,
+            stop_above: 80.0,
+        }
+    }
+}
+
+impl DerateConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.full_below >= self.stop_above {
+            return Err(format!(
+                "derate full_below {} must be under stop_above {}",
+                self.full_below, self.stop_above
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// PI fan control to a Pre temperature, replaces the curve
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct FanPiConfig {
+    pub target: f32,
+    /// Duty % per °C above target
+    pub kp: f32,
+    /// Duty % per °C second
+    pub ki: f32,
+}
+
+/// Fan tachometer input, gpio number
+#[derive(Debug, Deserialize, Clone)]
+pub struct TachConfig {
+    pub pin: u64,
+    #[serde(default = "TachConfig::default_pulses_per_rev")]
+    pub pulses_per_rev: u8,
+    /// Below this while driven the fan is stalled
+    pub min_rpm: u32,
+}
+
+impl TachConfig {
+    fn default_pulses_per_rev() -> u8 {
+        2
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum FanFaultAction {
+    /// Limit current to fault_derate of full
+    Derate,
+    /// End the session
+    Stop,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct FanConfig {
+    /// (°C, duty %) points sorted by temperature
+    pub curve: Vec<(f32, u8)>,
+    pub pi: Option<FanPiConfig>,
+    /// Duties below this turn the fan off, PWM is unreliable near 0
+    pub min_duty: u8,
+    /// Falling duty held this long before it drops
+    pub overrun_secs: u64,
+    pub tach: Option<TachConfig>,
+    /// Stalled fan or failed PWM
+    pub on_fault: FanFaultAction,
+    pub fault_derate: f32,
+}
+
+impl Default for FanConfig {
+    fn default() -> Self {
+        Self {
+            curve: vec![(50.0, 0), (70.0, 100)],
+            pi: None,
+            min_duty: 20,
+            overrun_secs: 20,
+            tach: None,
+            on_fault: FanFaultAction::Derate,
+            fault_derate: 0.5,
+        }
+    }
+}
+
+/// Fan PWM output under the sysfs pwm class
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PwmConfig {
+    pub root: String,
+    pub chip: u32,
+    pub channel: u32,
+    pub frequency: u32,
+}
+
+impl Default for PwmConfig {
+    fn default() -> Self {
+        Self {
+            root: "/sys/class/pwm".into(),
+            chip: 0,
+            channel: 0,
+            frequency: 100,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AppConfig {
     pub mqtt: MqttConfig,
     pub meter: MeterConfig,
+    #[serde(default)]
+    pub soc: SocConfig,
+    #[serde(default)]
+    pub precharge: PrechargeConfig,
+    #[serde(default)]
+    pub estop: EstopConfig,
+    #[serde(default)]
+    pub wear: WearConfig,
+    #[serde(default)]
+    pub feedback: FeedbackConfig,
+    #[serde(default)]
+    pub pre_supervisor: PreSupervisorConfig,
+    #[serde(default)]
+    pub pre_modules: PreModulesConfig,
+    #[serde(default)]
+    pub pre_status: PreStatusConfig,
+    #[serde(default)]
+    pub pre_poll: PrePollConfig,
+    #[serde(default)]
+    pub slew: SlewConfig,
+    #[serde(default)]
+    pub derate: DerateConfig,
+    #[serde(default)]
+    pub fan: FanConfig,
+    #[serde(default)]
+    pub pwm: PwmConfig,
+}
+
+impl AppConfig {
+    /// Rejects settings that parse but can't be acted on
+    fn validate(&self) -> Result<(), String> {
+        self.derate.validate()?;
+        if self.meter.source == MeterSource::Mqtt && self.meter.mqtt.topic.is_empty() {
+            return Err("meter source mqtt needs a [meter.mqtt] topic".to_string());
+        }
+        if !matches!(self.meter.serial.stop_bits, 1 | 2) {
+            return Err(format!(
+                "meter serial stop_bits {} must be 1 or 2",
+                self.meter.serial.stop_bits
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.fan.fault_derate) {
+            return Err(format!(
+                "fan fault_derate {} must be within 0 to 1",
+                self.fan.fault_derate
+            ));
+        }
+        Ok(())
+    }
 }
@@ -14,10 +14,11 @@ use std::error::Error;
 use tokio::time::{sleep, timeout, Instant};
 
 use crate::error::IndraError;
+use crate::pre_charger::energy::{EnergyDay, EnergySession};
 use crate::POOL;
 
-use super::meter::METER;
 use super::mqtt::{MqttChademo, CHADEMO_DATA};
+use super::wear::{LoadSwitchRow, OpenEvent, WearRecord};
 
 const DB_URL: &str = "sqlite://database.db";
 
@@ -59,13 +60,18 @@ pub struct ChademoDbRow {
     pub requested_amps: i16,
     pub fan: u8,
     pub meter_kw: f32,
+    pub pre_identity: Option<String>,
+    pub ac_kw: Option<f32>,
+    pub efficiency: Option<f32>,
+    pub meter_volts: Option<f32>,
+    pub meter_hz: Option<f32>,
+    pub meter_amps: Option<f32>,
+    pub meter_import_kwh: Option<f32>,
+    pub meter_export_kwh: Option<f32>,
 }
 impl From<MqttChademo> for ChademoDbRow {
     fn from(value: MqttChademo) -> Self {
-        let meter_kw = match METER.clone().try_read().as_deref() {
-            Ok(Some(val)) => val * 0.001,
-            _ => 0.0,
-        };
+        let meter = value.meter.unwrap_or_default();
         Self {
             id: 0,
             timestamp: Utc::now(),
@@ -76,7 +82,18 @@ impl From<MqttChademo> for ChademoDbRow {
             amps: value.amps,
             requested_amps: value.requested_amps as i16,
             fan: value.fan,
-            meter_kw,
+            meter_kw: meter.power * 0.001,
+            pre_identity: match value.pre_identity.is_empty() {
+                true => None,
+                false => Some(value.pre_identity.to_string()),
+            },
+            ac_kw: Some(value.ac_kw),
+            efficiency: value.efficiency,
+            meter_volts: meter.volts,
+            meter_hz: meter.hz,
+            meter_amps: meter.amps,
+            meter_import_kwh: meter.import_kwh,
+            meter_export_kwh: meter.export_kwh,
         }
     }
 }
@@ -93,6 +110,14 @@ impl Default for ChademoDbRow {
             requested_amps: Default::default(),
             fan: Default::default(),
             meter_kw: Default::default(),
+            pre_identity: Default::default(),
+            ac_kw: Default::default(),
+            efficiency: Default::default(),
+            meter_volts: Default::default(),
+            meter_hz: Default::default(),
+            meter_amps: Default::default(),
+            meter_import_kwh: Default::default(),
+            meter_export_kwh: Default::default(),
         }
     }
 }
@@ -123,6 +148,27 @@ impl Database {
         if create_tables {
             let _ = db.create_table().await;
         }
+        // added after first release, existing databases need them too
+        if let Err(e) = db.create_wear_tables().await {
+            log::error!("wear tables {e:?}");
+        }
+        for (column, definition) in [
+            ("pre_identity", "TEXT"),
+            ("ac_kw", "REAL"),
+            ("efficiency", "REAL"),
+            ("meter_volts", "REAL"),
+            ("meter_hz", "REAL"),
+            ("meter_amps", "REAL"),
+            ("meter_import_kwh", "REAL"),
+            ("meter_export_kwh", "REAL"),
+        ] {
+            if let Err(e) = db.add_column(column, definition).await {
+                log::error!("sensor_readings {column} {e:?}");
+            }
+        }
+        if let Err(e) = db.create_energy_table().await {
+            log::error!("energy table {e:?}");
+        }
         Ok(db)
     }
     pub async fn process_request(
@@ -143,7 +189,7 @@ impl Database {
         record: &ChademoDbRow,
     ) -> Result<SqliteQueryResult, Box<dyn Error>> {
         let mut conn = self.pool.acquire().await?;
-        Ok(sqlx::query("INSERT INTO sensor_readings (timestamp, dc_kw, soc, volts, temp, amps, requested_amps, fan, meter_kw) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")
+        Ok(sqlx::query("INSERT INTO sensor_readings (timestamp, dc_kw, soc, volts, temp, amps, requested_amps, fan, meter_kw, pre_identity, ac_kw, efficiency, meter_volts, meter_hz, meter_amps, meter_import_kwh, meter_export_kwh) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
             .bind(Utc::now())
             .bind(record.dc_kw)
             .bind(record.soc)
@@ -153,6 +199,14 @@ impl Database {
             .bind(record.requested_amps)
             .bind(record.fan)
             .bind(record.meter_kw)
+            .bind(&record.pre_identity)
+            .bind(record.ac_kw)
+            .bind(record.efficiency)
+            .bind(record.meter_volts)
+            .bind(record.meter_hz)
+            .bind(record.meter_amps)
+            .bind(record.meter_import_kwh)
+            .bind(record.meter_export_kwh)
             .execute(&mut *conn)
             .await
             ?)
@@ -196,6 +250,152 @@ impl Database {
         .fetch_all(&self.pool)
         .await?)
     }
+    /// Counts one close -> open cycle, logs the open if it was under load
+    pub async fn add_wear(&self, event: &OpenEvent) -> Result<SqliteQueryResult, Box<dyn Error>> {
+        let mut conn = self.pool.acquire().await?;
+        if event.under_load() {
+            sqlx::query(
+                "INSERT INTO load_switch_events (timestamp, device, amps) VALUES (?, ?, ?)",
+            )
+            .bind(Utc::now())
+            .bind(event.device.name())
+            .bind(event.amps)
+            .execute(&mut *conn)
+            .await?;
+        }
+        Ok(sqlx::query(
+            r#"
+            INSERT INTO wear_counters (device, cycles, load_switches, on_secs) VALUES (?, 1, ?, ?)
+            ON CONFLICT(device) DO UPDATE SET
+                cycles = cycles + 1,
+                load_switches = load_switches + excluded.load_switches,
+                on_secs = on_secs + excluded.on_secs
+            "#,
+        )
+        .bind(event.device.name())
+        .bind(event.under_load() as i64)
+        .bind(event.on_secs)
+        .execute(&mut *conn)
+        .await?)
+    }
+    pub async fn get_wear_records(&self) -> Result<Vec<WearRecord>, Box<dyn Error>> {
+        Ok(
+            sqlx::query_as::<_, WearRecord>("SELECT * FROM wear_counters")
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+    pub async fn get_wear_record(&self, device: &str) -> Result<WearRecord, Box<dyn Error>> {
+        Ok(
+            sqlx::query_as::<_, WearRecord>("SELECT * FROM wear_counters WHERE device = ?")
+                .bind(device)
+                .fetch_one(&self.pool)
+                .await?,
+        )
+    }
+    pub async fn get_load_switches(&self) -> Result<Vec<LoadSwitchRow>, Box<dyn Error>> {
+        Ok(sqlx::query_as::<_, LoadSwitchRow>(
+            "SELECT * FROM load_switch_events ORDER BY timestamp DESC",
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+    pub async fn create_wear_tables(&self) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS wear_counters (
+                device TEXT PRIMARY KEY,
+                cycles INTEGER NOT NULL DEFAULT 0,
+                load_switches INTEGER NOT NULL DEFAULT 0,
+                on_secs REAL NOT NULL DEFAULT 0
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS load_switch_events (
+                id INTEGER PRIMARY KEY,
+                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+                device TEXT,
+                amps REAL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+    pub async fn add_energy_session(
+        &self,
+        session: &EnergySession,
+    ) -> Result<SqliteQueryResult, Box<dyn Error>> {
+        let totals = &session.totals;
+        Ok(sqlx::query(
+            "INSERT INTO energy_sessions (start, end, ac_in_wh, ac_out_wh, dc_in_wh, dc_out_wh) VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(session.start)
+        .bind(session.end)
+        .bind(totals.ac_in_wh)
+        .bind(totals.ac_out_wh)
+        .bind(totals.dc_in_wh)
+        .bind(totals.dc_out_wh)
+        .execute(&self.pool)
+        .await?)
+    }
+    /// Session totals per local day, newest first
+    pub async fn get_energy_days(&self, days: u32) -> Result<Vec<EnergyDay>, Box<dyn Error>> {
+        let since = Utc::now() - Duration::days(days.into());
+        Ok(sqlx::query_as::<_, EnergyDay>(
+            r#"
+            SELECT date(start, 'localtime') AS day,
+                COUNT(*) AS sessions,
+                SUM(ac_in_wh) AS ac_in_wh,
+                SUM(ac_out_wh) AS ac_out_wh,
+                SUM(dc_in_wh) AS dc_in_wh,
+                SUM(dc_out_wh) AS dc_out_wh
+            FROM energy_sessions WHERE start >= ?
+            GROUP BY day ORDER BY day DESC
+            "#,
+        )
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+    pub async fn create_energy_table(&self) -> Result<(), Box<dyn Error>> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS energy_sessions (
+                id INTEGER PRIMARY KEY,
+                start DATETIME,
+                end DATETIME,
+                ac_in_wh REAL,
+                ac_out_wh REAL,
+                dc_in_wh REAL,
+                dc_out_wh REAL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+    /// sensor_readings in databases created before the column was added
+    async fn add_column(&self, column: &str, definition: &str) -> Result<(), Box<dyn Error>> {
+        let columns: Vec<(String,)> =
+            sqlx::query_as("SELECT name FROM pragma_table_info('sensor_readings')")
+                .fetch_all(&self.pool)
+                .await?;
+        if !columns.is_empty() && !columns.iter().any(|(name,)| name == column) {
+            sqlx::query(&format!(
+                "ALTER TABLE sensor_readings ADD COLUMN {column} {definition}"
+            ))
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
     pub async fn create_table(&self) -> Result<(), Box<dyn Error>> {
         sqlx::query(
             r#"
@@ -209,7 +409,15 @@ impl Database {
                 amps REAL,
                 requested_amps INTEGER,
                 fan INTEGER,
-                meter_kw REAL
+                meter_kw REAL,
+                pre_identity TEXT,
+                ac_kw REAL,
+                efficiency REAL,
+                meter_volts REAL,
+                meter_hz REAL,
+                meter_amps REAL,
+                meter_import_kwh REAL,
+                meter_export_kwh REAL
             )
             "#,
         )
@@ -230,6 +438,7 @@ pub enum Parameters {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::data_io::wear::Device;
     #[tokio::test]
     async fn test_connect_db() {
         let db = Database::new().await;
@@ -251,4 +460,36 @@ mod test {
             println!("[{:?}] ", result);
         }
     }
+    #[tokio::test]
+    async fn test_add_wear() {
+        let db = Database::new().await.unwrap();
+        let event = OpenEvent {
+            device: Device::PlugLock,
+            amps: 5.0,
+            on_secs: 60.0,
+        };
+        let before = db
+            .get_wear_record("plug_lock")
+            .await
+            .map_or(0, |r| r.cycles);
+        assert!(db.add_wear(&event).await.is_ok());
+        let after = db.get_wear_record("plug_lock").await.unwrap();
+        assert_eq!(after.cycles, before + 1);
+        assert!(!db.get_load_switches().await.unwrap().is_empty());
+    }
+    #[tokio::test]
+    async fn test_energy_days() {
+        let db = Database::new().await.unwrap();
+        let mut session = EnergySession {
+            start: Utc::now(),
+            end: Utc::now(),
+            totals: Default::default(),
+        };
+        session.totals.ac_in_wh = 1000.0;
+        session.totals.dc_out_wh = 900.0;
+        assert!(db.add_energy_session(&session).await.is_ok());
+        let days = db.get_energy_days(1).await.unwrap();
+        assert!(days[0].sessions >= 1);
+        assert!(days[0].totals.ac_in_wh >= 1000.0);
+    }
 }
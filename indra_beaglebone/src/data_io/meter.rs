@@ -1,121 +1,207 @@
-use super::config::MeterConfig;
+use super::config::{MeterConfig, MeterProfile, MeterSource, RegisterMap};
+use super::modbus::{f32_from_registers, Endpoint, Function, ModbusClient, Transport};
+use super::mqtt_meter::mqtt_meter;
 use crate::data_io::mqtt::CHADEMO_DATA;
 use crate::error::IndraError;
+use serde::Serialize;
 use std::{net::SocketAddr, sync::Arc};
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
 use tokio::sync::RwLock;
-use tokio::time::timeout;
-use tokio::time::Instant;
-use tokio::{
-    net::TcpStream,
-    sync::Mutex,
-    time::{sleep, Duration},
-};
+use tokio::time::{sleep, Duration, Instant};
 
 lazy_static::lazy_static! {
-    pub static ref METER: Arc<RwLock<Option<f32>>> = Arc::new(RwLock::new(Some(0f32)));
+    pub static ref METER: Arc<RwLock<Option<MeterReading>>> =
+        Arc::new(RwLock::new(Some(MeterReading::default())));
+}
+
+/// One meter poll, values the profile lacks are None
+#[derive(Serialize, Default, Debug, Clone, Copy, PartialEq)]
+pub struct MeterReading {
+    /// W, positive importing from the grid
+    pub power: f32,
+    pub volts: Option<f32>,
+    pub hz: Option<f32>,
+    pub amps: Option<f32>,
+    pub import_kwh: Option<f32>,
+    pub export_kwh: Option<f32>,
+}
+
+impl MeterProfile {
+    /// Eastron input registers
+    pub fn register_map(&self) -> RegisterMap {
+        let single_phase = RegisterMap {
+            function: Function::ReadInput,
+            power: 0x0c,
+            volts: Some(0x00),
+            hz: Some(0x46),
+            amps: Some(0x06),
+            import_kwh: Some(0x48),
+            export_kwh: Some(0x4a),
+        };
+        match self {
+            MeterProfile::Sdm120 | MeterProfile::Sdm230 => single_phase,
+            // total power, average line to neutral volts, sum of line currents
+            MeterProfile::Sdm630 | MeterProfile::Sdm72 => RegisterMap {
+                power: 0x34,
+                volts: Some(0x2a),
+                amps: Some(0x30),
+                ..single_phase
+            },
+        }
+    }
 }
 
 pub async fn meter(config: MeterConfig) -> Result<(), IndraError> {
     log::info!("Starting Meter thread {}", tokio::task::id());
-    // let config = &APP_CONFIG.clone();s
-    let address = config.address.clone();
-    let socket_addr: SocketAddr = address
-        .parse::<SocketAddr>()
-        .map_err(|e| IndraError::SocketError(e))?;
+    if config.source == MeterSource::Mqtt {
+        return mqtt_meter(config.mqtt).await;
+    }
+    let endpoint = match config.transport {
+        Transport::Serial => Endpoint::Serial(config.serial.clone()),
+        Transport::RtuOverTcp | Transport::ModbusTcp => Endpoint::Tcp(
+            config
+                .address
+                .parse::<SocketAddr>()
+                .map_err(|e| IndraError::SocketError(e))?,
+        ),
+    };
+    let map = config
+        .registers
+        .unwrap_or_else(|| config.profile.register_map());
     log::info!(
-        "Connecting to RTU meter: IP:{:?} port:{}",
-        socket_addr.ip(),
-        socket_addr.port()
+        "Connecting to {:?} {:?} meter: {endpoint} slave:{} {map:?}",
+        config.profile,
+        config.transport,
+        config.slave_id
+    );
+    let mut client = ModbusClient::new(
+        endpoint,
+        config.transport,
+        Duration::from_millis(config.timeout_ms),
     );
+    let period = Duration::from_millis(config.period_ms);
     loop {
-        let mut stream = TcpStream::connect(socket_addr)
-            .await
-            .map_err(|e| IndraError::SocketConnectError(e))?;
-        let (mut rx, mut tx) = stream.split();
-
-        // Raw modbus params for SDM230 @ 1hz
-        let device_id = 1;
-        let function_code = 0x04; // Read Holding Registers
-        let starting_address = 0x0c;
-        let quantity = 2;
-
-        let request =
-            energy_modbus_rtu_request(device_id, function_code, starting_address, quantity);
-        log::info!("SDM230 modbus PDU: {request:02x?}");
-        let mut val = 0.1f32;
-
-        'inner: loop {
-            let mut buf = [0u8; 24];
-            let instant = Instant::now();
-            if let Err(e) = tx.write(&request).await {
-                log::error!("TCP write error {e:?}");
-                break 'inner;
-            }
-
-            match timeout(Duration::from_millis(400), rx.read(&mut buf)).await {
-                Ok(Ok(_)) => {
-                    // Strange blank meter readings
-                    if buf[3..=6] != [0, 0, 0, 0] {
-                        val =
-                            f32::from_be_bytes(buf[3..=6].try_into().unwrap_or(val.to_be_bytes()));
-                    }
-                }
-                Err(e) => {
-                    log::error!("Meter TCP timeout {e:?}");
-                    break 'inner;
-                }
-                _ => {
-                    log::error!("Meter TCP read error");
-                    break 'inner;
-                }
-            };
-            log::info!("Meter value {} ", val);
-            {
-                *METER.clone().write().await = Some(val);
-            }
-            {
-                CHADEMO_DATA.clone().write().await.from_meter(val);
+        let instant = Instant::now();
+        let reading = match read_meter(&mut client, config.slave_id, &map).await {
+            Ok(reading) => {
+                log::info!("Meter value {} ", reading.power);
+                Some(reading)
             }
-            if instant.elapsed() < Duration::from_millis(500) {
-                sleep(Duration::from_millis(500) - instant.elapsed()).await
+            Err(e) => {
+                log::error!("Meter read failed {e}");
+                None
             }
+        };
+        set_reading(reading).await;
+        if instant.elapsed() < period {
+            sleep(period - instant.elapsed()).await
         }
-        *METER.clone().write().await = None;
-        drop(stream)
     }
 }
 
-fn energy_modbus_rtu_request(
-    device_id: u8,
-    function_code: u8,
-    starting_address: u16,
-    quantity: u16,
-) -> [u8; 8] {
-    let mut request = [0u8; 8];
-    request[0] = device_id;
-    request[1] = function_code;
-    [request[2], request[3]] = starting_address.to_be_bytes();
-    [request[4], request[5]] = quantity.to_be_bytes();
-    let crc = calculate_crc(&request[0..6]);
-    [request[6], request[7]] = crc.to_le_bytes();
-    request
+/// Shared by every meter source, None while offline
+pub async fn set_reading(reading: Option<MeterReading>) {
+    *METER.clone().write().await = reading;
+    CHADEMO_DATA.clone().write().await.from_meter(reading);
 }
 
-#[inline]
-fn calculate_crc(data: &[u8]) -> u16 {
-    let mut crc = 0xFFFFu16;
-    for byte in data {
-        crc ^= u16::from(*byte);
-        for _ in 0..8 {
-            if (crc & 1) != 0 {
-                crc >>= 1;
-                crc ^= 0xA001;
-            } else {
-                crc >>= 1;
+/// Power is required, other values are skipped if the meter rejects them
+async fn read_meter(
+    client: &mut ModbusClient,
+    slave: u8,
+    map: &RegisterMap,
+) -> Result<MeterReading, IndraError> {
+    let mut reading = MeterReading {
+        power: read_float(client, slave, map.function, map.power).await?,
+        ..Default::default()
+    };
+    for (register, value) in [
+        (map.volts, &mut reading.volts),
+        (map.hz, &mut reading.hz),
+        (map.amps, &mut reading.amps),
+        (map.import_kwh, &mut reading.import_kwh),
+        (map.export_kwh, &mut reading.export_kwh),
+    ] {
+        let Some(register) = register else {
+            continue;
+        };
+        match read_float(client, slave, map.function, register).await {
+            Ok(val) => *value = Some(val),
+            Err(IndraError::ModbusException(code)) => {
+                log::warn!("Meter register {register:#06x} {code}")
             }
+            Err(e) => return Err(e),
         }
     }
-    crc
+    Ok(reading)
+}
+
+async fn read_float(
+    client: &mut ModbusClient,
+    slave: u8,
+    function: Function,
+    register: u16,
+) -> Result<f32, IndraError> {
+    let registers = match function {
+        Function::ReadInput => client.read_input_registers(slave, register, 2).await,
+        Function::ReadHolding => client.read_holding_registers(slave, register, 2).await,
+    }?;
+    f32_from_registers(&registers)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data_io::modbus::crc16;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    /// RTU meter answering each register with its own address, frequency unsupported
+    async fn mock_meter() -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 8];
+            while stream.read_exact(&mut request).await.is_ok() {
+                let register = u16::from_be_bytes([request[2], request[3]]);
+                let mut response = match register {
+                    0x46 => vec![request[0], request[1] | 0x80, 0x02],
+                    _ => {
+                        let mut response = vec![request[0], request[1], 4];
+                        response.extend((register as f32).to_be_bytes());
+                        response
+                    }
+                };
+                response.extend(crc16(&response).to_le_bytes());
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        address
+    }
+    #[tokio::test]
+    async fn read_meter_test() {
+        let address = mock_meter().await;
+        let mut client = ModbusClient::new(
+            Endpoint::Tcp(address),
+            Transport::RtuOverTcp,
+            Duration::from_millis(400),
+        );
+        let map = MeterProfile::Sdm630.register_map();
+        let reading = read_meter(&mut client, 1, &map).await.unwrap();
+        assert_eq!(reading.power, 52.0);
+        assert_eq!(reading.volts, Some(42.0));
+        assert_eq!(reading.amps, Some(48.0));
+        assert_eq!(reading.hz, None);
+        assert_eq!(reading.export_kwh, Some(74.0));
+
+        let map = RegisterMap {
+            volts: None,
+            hz: None,
+            amps: None,
+            import_kwh: None,
+            export_kwh: None,
+            ..MeterProfile::Sdm230.register_map()
+        };
+        let reading = read_meter(&mut client, 1, &map).await.unwrap();
+        assert_eq!(reading.power, 12.0);
+        assert_eq!(reading.import_kwh, None);
+    }
 }
@@ -2,5 +2,8 @@ pub(crate) mod config;
 // pub(crate) mod keyboard;
 pub(crate) mod db;
 pub(crate) mod meter;
+pub(crate) mod modbus;
 pub(crate) mod mqtt;
+pub(crate) mod mqtt_meter;
 pub(crate) mod panel;
+pub(crate) mod wear;
@@ -0,0 +1,645 @@
+use super::config::{Parity, SerialConfig};
+use crate::error::IndraError;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::{sleep, sleep_until, timeout, Duration, Instant},
+};
+use tokio_serial::SerialPortBuilderExt;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Registers a single read may return
+const MAX_REGISTERS: u16 = 125;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Function {
+    ReadHolding,
+    ReadInput,
+}
+
+impl From<Function> for u8 {
+    fn from(function: Function) -> u8 {
+        match function {
+            Function::ReadHolding => 0x03,
+            Function::ReadInput => 0x04,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExceptionCode(pub u8);
+
+impl ExceptionCode {
+    pub fn description(&self) -> &'static str {
+        match self.0 {
+            0x01 => "Illegal function",
+            0x02 => "Illegal data address",
+            0x03 => "Illegal data value",
+            0x04 => "Slave device failure",
+            0x05 => "Acknowledge",
+            0x06 => "Slave device busy",
+            0x08 => "Memory parity error",
+            0x0a => "Gateway path unavailable",
+            0x0b => "Gateway target failed to respond",
+            _ => "Unknown exception code",
+        }
+    }
+}
+
+impl std::fmt::Display for ExceptionCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02x} {}", self.0, self.description())
+    }
+}
+
+/// Framing on the wire
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+    /// RTU frames tunnelled through a serial gateway
+    #[default]
+    RtuOverTcp,
+    /// MBAP header, no CRC
+    ModbusTcp,
+    /// RTU frames on a local UART
+    Serial,
+}
+
+/// Where requests go, the transport picks the framing
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Serial(SerialConfig),
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp(address) => write!(f, "{address}"),
+            Endpoint::Serial(config) => write!(f, "{}@{}", config.path, config.baud),
+        }
+    }
+}
+
+impl From<Parity> for tokio_serial::Parity {
+    fn from(parity: Parity) -> Self {
+        match parity {
+            Parity::None => tokio_serial::Parity::None,
+            Parity::Even => tokio_serial::Parity::Even,
+            Parity::Odd => tokio_serial::Parity::Odd,
+        }
+    }
+}
+
+/// TCP socket or serial port
+trait Link: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Link for T {}
+
+/// Register read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadRequest {
+    pub slave: u8,
+    pub function: Function,
+    pub address: u16,
+    pub quantity: u16,
+}
+
+impl ReadRequest {
+    fn pdu(&self) -> [u8; 5] {
+        let [address_hi, address_lo] = self.address.to_be_bytes();
+        let [quantity_hi, quantity_lo] = self.quantity.to_be_bytes();
+        [
+            self.function.into(),
+            address_hi,
+            address_lo,
+            quantity_hi,
+            quantity_lo,
+        ]
+    }
+    pub fn to_rtu(&self) -> [u8; 8] {
+        let mut request = [0u8; 8];
+        request[0] = self.slave;
+        request[1..6].copy_from_slice(&self.pdu());
+        [request[6], request[7]] = crc16(&request[0..6]).to_le_bytes();
+        request
+    }
+    pub fn to_mbap(&self, transaction: u16) -> [u8; 12] {
+        let mut request = [0u8; 12];
+        [request[0], request[1]] = transaction.to_be_bytes();
+        // protocol 0, length counts the unit ID & PDU
+        [request[4], request[5]] = 6u16.to_be_bytes();
+        request[6] = self.slave;
+        request[7..].copy_from_slice(&self.pdu());
+        request
+    }
+    /// Checks a complete RTU response & returns its registers
+    pub fn parse_rtu(&self, frame: &[u8]) -> Result<Vec<u16>, IndraError> {
+        let len = frame.len();
+        if len < 5 {
+            return Err(IndraError::ModbusInvalid("short frame"));
+        }
+        let crc = u16::from_le_bytes([frame[len - 2], frame[len - 1]]);
+        if crc16(&frame[..len - 2]) != crc {
+            return Err(IndraError::ModbusCrc);
+        }
+        if frame[0] != self.slave {
+            return Err(IndraError::ModbusInvalid("slave ID mismatch"));
+        }
+        self.parse_pdu(&frame[1..len - 2])
+    }
+    /// Checks a complete MBAP response & returns its registers
+    pub fn parse_mbap(&self, transaction: u16, frame: &[u8]) -> Result<Vec<u16>, IndraError> {
+        if frame.len() < 9 {
+            return Err(IndraError::ModbusInvalid("short frame"));
+        }
+        if u16::from_be_bytes([frame[0], frame[1]]) != transaction {
+            return Err(IndraError::ModbusInvalid("transaction ID mismatch"));
+        }
+        if frame[2..4] != [0, 0] {
+            return Err(IndraError::ModbusInvalid("protocol ID not Modbus"));
+        }
+        if u16::from_be_bytes([frame[4], frame[5]]) as usize != frame.len() - 6 {
+            return Err(IndraError::ModbusInvalid("MBAP length mismatch"));
+        }
+        if frame[6] != self.slave {
+            return Err(IndraError::ModbusInvalid("slave ID mismatch"));
+        }
+        self.parse_pdu(&frame[7..])
+    }
+    fn parse_pdu(&self, pdu: &[u8]) -> Result<Vec<u16>, IndraError> {
+        let function = u8::from(self.function);
+        if pdu[0] == function | 0x80 {
+            return Err(IndraError::ModbusException(ExceptionCode(pdu[1])));
+        }
+        if pdu[0] != function {
+            return Err(IndraError::ModbusInvalid("function code mismatch"));
+        }
+        let count = pdu[1] as usize;
+        if count != self.quantity as usize * 2 || pdu.len() != count + 2 {
+            return Err(IndraError::ModbusInvalid("byte count mismatch"));
+        }
+        Ok(pdu[2..]
+            .chunks_exact(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]))
+            .collect())
+    }
+}
+
+/// Full RTU response length from the first 3 bytes
+fn response_len(header: &[u8; 3]) -> usize {
+    match header[1] & 0x80 != 0 {
+        // slave, function, exception code, CRC
+        true => 5,
+        false => header[2] as usize + 5,
+    }
+}
+
+/// IEEE 754 float from two registers, high word first
+pub fn f32_from_registers(registers: &[u16]) -> Result<f32, IndraError> {
+    match registers {
+        [high, low] => Ok(f32::from_bits(((*high as u32) << 16) | *low as u32)),
+        _ => Err(IndraError::ModbusInvalid("float needs 2 registers")),
+    }
+}
+
+#[inline]
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            if (crc & 1) != 0 {
+                crc >>= 1;
+                crc ^= 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Reconnect delay, doubles after each failed attempt
+#[derive(Debug)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Option<Duration>,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            delay: None,
+        }
+    }
+    /// Wait before the next attempt, none after a success
+    pub fn delay(&self) -> Duration {
+        self.delay.unwrap_or_default()
+    }
+    pub fn failed(&mut self) {
+        self.delay = Some(match self.delay {
+            None => self.min,
+            Some(delay) => (delay * 2).min(self.max),
+        });
+    }
+    pub fn succeeded(&mut self) {
+        self.delay = None
+    }
+}
+
+/// Modbus client, one request at a time
+///
+/// The connection is dropped after any failure except an exception response and
+/// reopened on the next request.
+pub struct ModbusClient {
+    endpoint: Endpoint,
+    transport: Transport,
+    stream: Option<Box<dyn Link>>,
+    transaction: u16,
+    timeout: Duration,
+    backoff: Backoff,
+    /// Bus silence required before the next request
+    silence: Duration,
+    last_frame: Option<Instant>,
+}
+
+impl ModbusClient {
+    pub fn new(endpoint: Endpoint, transport: Transport, timeout: Duration) -> Self {
+        let silence = match &endpoint {
+            Endpoint::Tcp(_) => Duration::ZERO,
+            Endpoint::Serial(config) => config.inter_frame(),
+        };
+        Self {
+            endpoint,
+            transport,
+            stream: None,
+            transaction: 0,
+            timeout,
+            backoff: Backoff::new(MIN_BACKOFF, MAX_BACKOFF),
+            silence,
+            last_frame: None,
+        }
+    }
+    pub async fn read_holding_registers(
+        &mut self,
+        slave: u8,
+        address: u16,
+        quantity: u16,
+    ) -> Result<Vec<u16>, IndraError> {
+        self.read(ReadRequest {
+            slave,
+            function: Function::ReadHolding,
+            address,
+            quantity,
+        })
+        .await
+    }
+
+    pub async fn read_input_registers(
+        &mut self,
+        slave: u8,
+        address: u16,
+        quantity: u16,
+    ) -> Result<Vec<u16>, IndraError> {
+        self.read(ReadRequest {
+            slave,
+            function: Function::ReadInput,
+            address,
+            quantity,
+        })
+        .await
+    }
+
+    pub async fn read(&mut self, request: ReadRequest) -> Result<Vec<u16>, IndraError> {
+        if request.quantity == 0 || request.quantity > MAX_REGISTERS {
+            return Err(IndraError::ModbusInvalid("register quantity out of range"));
+        }
+        self.transaction = self.transaction.wrapping_add(1);
+        let (transport, transaction, response_timeout) =
+            (self.transport, self.transaction, self.timeout);
+        if let Some(last_frame) = self.last_frame {
+            sleep_until(last_frame + self.silence).await;
+        }
+        let stream = self.connect().await?;
+        let exchange = async {
+            match transport {
+                Transport::ModbusTcp => mbap_exchange(stream, &request, transaction).await,
+                Transport::RtuOverTcp | Transport::Serial => rtu_exchange(stream, &request).await,
+            }
+        };
+        let result = match timeout(response_timeout, exchange).await {
+            Ok(result) => result,
+            Err(_) => Err(IndraError::Timeout),
+        };
+        self.last_frame = Some(Instant::now());
+        if let Err(ref e) = result {
+            // a late or partial response would be read as the next answer
+            if !matches!(e, IndraError::ModbusException(_)) {
+                log::warn!("Modbus {} dropped after {e}", self.endpoint);
+                self.stream = None;
+            }
+        }
+        result
+    }
+
+    /// Open connection, waits out the backoff before reconnecting
+    async fn connect(&mut self) -> Result<&mut Box<dyn Link>, IndraError> {
+        let stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => {
+                sleep(self.backoff.delay()).await;
+                match open(&self.endpoint).await {
+                    Ok(stream) => {
+                        log::info!("Modbus connected to {}", self.endpoint);
+                        self.backoff.succeeded();
+                        stream
+                    }
+                    Err(e) => {
+                        self.backoff.failed();
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        Ok(self.stream.insert(stream))
+    }
+}
+
+async fn open(endpoint: &Endpoint) -> Result<Box<dyn Link>, IndraError> {
+    match endpoint {
+        Endpoint::Tcp(address) => match timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await
+        {
+            Ok(Ok(stream)) => Ok(Box::new(stream)),
+            Ok(Err(e)) => Err(IndraError::SocketConnectError(e)),
+            Err(_) => Err(IndraError::Timeout),
+        },
+        Endpoint::Serial(config) => {
+            // 1 or 2, checked at config load
+            let stop_bits = match config.stop_bits {
+                2 => tokio_serial::StopBits::Two,
+                _ => tokio_serial::StopBits::One,
+            };
+            let port = tokio_serial::new(&config.path, config.baud)
+                .data_bits(tokio_serial::DataBits::Eight)
+                .parity(config.parity.into())
+                .stop_bits(stop_bits)
+                .open_native_async()
+                .map_err(IndraError::SerialOpen)?;
+            Ok(Box::new(port))
+        }
+    }
+}
+
+async fn rtu_exchange(
+    stream: &mut Box<dyn Link>,
+    request: &ReadRequest,
+) -> Result<Vec<u16>, IndraError> {
+    let frame = request.to_rtu();
+    log::trace!("Tx>>Modbus {frame:02x?}");
+    stream
+        .write_all(&frame)
+        .await
+        .map_err(IndraError::ModbusIo)?;
+    let mut header = [0u8; 3];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(IndraError::ModbusIo)?;
+    let mut response = vec![0u8; response_len(&header)];
+    response[..3].copy_from_slice(&header);
+    stream
+        .read_exact(&mut response[3..])
+        .await
+        .map_err(IndraError::ModbusIo)?;
+    log::trace!("Rx<<Modbus {response:02x?}");
+    request.parse_rtu(&response)
+}
+
+async fn mbap_exchange(
+    stream: &mut Box<dyn Link>,
+    request: &ReadRequest,
+    transaction: u16,
+) -> Result<Vec<u16>, IndraError> {
+    let frame = request.to_mbap(transaction);
+    log::trace!("Tx>>Modbus {frame:02x?}");
+    stream
+        .write_all(&frame)
+        .await
+        .map_err(IndraError::ModbusIo)?;
+    let mut header = [0u8; 7];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(IndraError::ModbusIo)?;
+    // unit ID plus at least a function & exception code
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if !(3..=254).contains(&len) {
+        return Err(IndraError::ModbusInvalid("MBAP length out of range"));
+    }
+    let mut response = vec![0u8; len + 6];
+    response[..7].copy_from_slice(&header);
+    stream
+        .read_exact(&mut response[7..])
+        .await
+        .map_err(IndraError::ModbusIo)?;
+    log::trace!("Rx<<Modbus {response:02x?}");
+    request.parse_mbap(transaction, &response)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn with_crc(data: &[u8]) -> Vec<u8> {
+        let mut frame = data.to_vec();
+        frame.extend(crc16(data).to_le_bytes());
+        frame
+    }
+    const REQUEST: ReadRequest = ReadRequest {
+        slave: 1,
+        function: Function::ReadInput,
+        address: 0x0c,
+        quantity: 2,
+    };
+    #[test]
+    fn crc_test() {
+        assert_eq!(crc16(b"123456789"), 0x4b37);
+        assert_eq!(
+            REQUEST.to_rtu(),
+            [0x01, 0x04, 0x00, 0x0c, 0x00, 0x02, 0xb1, 0xc8]
+        );
+    }
+    #[test]
+    fn parse_test() {
+        let watts = 1234.5f32.to_be_bytes();
+        let frame = with_crc(&[1, 4, 4, watts[0], watts[1], watts[2], watts[3]]);
+        assert_eq!(response_len(&[frame[0], frame[1], frame[2]]), frame.len());
+        let registers = REQUEST.parse_rtu(&frame).unwrap();
+        assert_eq!(f32_from_registers(&registers).unwrap(), 1234.5);
+
+        // zero is a valid reading
+        let frame = with_crc(&[1, 4, 4, 0, 0, 0, 0]);
+        assert_eq!(REQUEST.parse_rtu(&frame).unwrap(), vec![0, 0]);
+
+        let mut corrupt = frame.clone();
+        corrupt[4] = 1;
+        assert!(matches!(
+            REQUEST.parse_rtu(&corrupt),
+            Err(IndraError::ModbusCrc)
+        ));
+        let other_slave = with_crc(&[2, 4, 4, 0, 0, 0, 0]);
+        assert!(matches!(
+            REQUEST.parse_rtu(&other_slave),
+            Err(IndraError::ModbusInvalid(_))
+        ));
+        let short = with_crc(&[1, 4, 2, 0, 0]);
+        assert!(matches!(
+            REQUEST.parse_rtu(&short),
+            Err(IndraError::ModbusInvalid(_))
+        ));
+    }
+    #[test]
+    fn exception_test() {
+        let frame = with_crc(&[1, 0x84, 0x02]);
+        assert_eq!(response_len(&[frame[0], frame[1], frame[2]]), 5);
+        match REQUEST.parse_rtu(&frame) {
+            Err(IndraError::ModbusException(code)) => {
+                assert_eq!(code.description(), "Illegal data address")
+            }
+            other => panic!("{other:?}"),
+        }
+    }
+    #[test]
+    fn mbap_test() {
+        assert_eq!(
+            REQUEST.to_mbap(0x1234),
+            [0x12, 0x34, 0, 0, 0, 6, 1, 0x04, 0x00, 0x0c, 0x00, 0x02]
+        );
+        let frame = [0x12, 0x34, 0, 0, 0, 7, 1, 4, 4, 0x44, 0x9a, 0x50, 0x00];
+        let registers = REQUEST.parse_mbap(0x1234, &frame).unwrap();
+        assert_eq!(f32_from_registers(&registers).unwrap(), 1234.5);
+        assert!(matches!(
+            REQUEST.parse_mbap(0x1235, &frame),
+            Err(IndraError::ModbusInvalid("transaction ID mismatch"))
+        ));
+        let exception = [0x12, 0x34, 0, 0, 0, 3, 1, 0x84, 0x0b];
+        assert!(matches!(
+            REQUEST.parse_mbap(0x1234, &exception),
+            Err(IndraError::ModbusException(ExceptionCode(0x0b)))
+        ));
+    }
+    /// Answers each request with one reading, framed for the transport
+    async fn mock_server(transport: Transport, watts: f32) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let value = watts.to_be_bytes();
+            loop {
+                let response = match transport {
+                    Transport::RtuOverTcp | Transport::Serial => {
+                        let mut request = [0u8; 8];
+                        if stream.read_exact(&mut request).await.is_err() {
+                            break;
+                        }
+                        let mut pdu = vec![request[0], request[1], 4];
+                        pdu.extend(value);
+                        with_crc(&pdu)
+                    }
+                    Transport::ModbusTcp => {
+                        let mut request = [0u8; 12];
+                        if stream.read_exact(&mut request).await.is_err() {
+                            break;
+                        }
+                        let mut frame = request[..4].to_vec();
+                        frame.extend([0, 7, request[6], request[7], 4]);
+                        frame.extend(value);
+                        frame
+                    }
+                };
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        address
+    }
+    #[tokio::test]
+    async fn client_test() {
+        for transport in [Transport::RtuOverTcp, Transport::ModbusTcp] {
+            let address = mock_server(transport, -2345.5).await;
+            let mut client = ModbusClient::new(
+                Endpoint::Tcp(address),
+                transport,
+                Duration::from_millis(400),
+            );
+            for _ in 0..3 {
+                let registers = client.read_input_registers(1, 0x0c, 2).await.unwrap();
+                assert_eq!(f32_from_registers(&registers).unwrap(), -2345.5);
+            }
+            assert!(matches!(
+                client.read_input_registers(1, 0x0c, 0).await,
+                Err(IndraError::ModbusInvalid(_))
+            ));
+        }
+    }
+    #[tokio::test]
+    async fn serial_test() {
+        let (port, mut meter) = tokio_serial::SerialStream::pair().unwrap();
+        let config = SerialConfig {
+            inter_frame_ms: Some(20),
+            ..Default::default()
+        };
+        let mut client = ModbusClient::new(
+            Endpoint::Serial(config),
+            Transport::Serial,
+            Duration::from_millis(400),
+        );
+        client.stream = Some(Box::new(port));
+        tokio::spawn(async move {
+            let mut request = [0u8; 8];
+            // answers slave 1 only, like a shared bus
+            while meter.read_exact(&mut request).await.is_ok() {
+                if request[0] == 1 {
+                    let response = with_crc(&[1, 4, 4, 0x44, 0x9a, 0x50, 0x00]);
+                    meter.write_all(&response).await.unwrap();
+                }
+            }
+        });
+        let registers = client.read_input_registers(1, 0x0c, 2).await.unwrap();
+        assert_eq!(f32_from_registers(&registers).unwrap(), 1234.5);
+        let instant = Instant::now();
+        client.read_input_registers(1, 0x0c, 2).await.unwrap();
+        assert!(instant.elapsed() >= Duration::from_millis(20));
+        assert!(matches!(
+            client.read_input_registers(2, 0x0c, 2).await,
+            Err(IndraError::Timeout)
+        ));
+        assert!(client.stream.is_none());
+    }
+    #[test]
+    fn inter_frame_test() {
+        let config = |baud| SerialConfig {
+            baud,
+            ..Default::default()
+        };
+        assert_eq!(config(9600).inter_frame(), Duration::from_micros(4010));
+        assert_eq!(config(38400).inter_frame(), Duration::from_micros(1750));
+    }
+    #[test]
+    fn backoff_test() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        assert_eq!(backoff.delay(), Duration::ZERO);
+        for secs in [1, 2, 4, 5, 5] {
+            backoff.failed();
+            assert_eq!(backoff.delay(), Duration::from_secs(secs));
+        }
+        backoff.succeeded();
+        assert_eq!(backoff.delay(), Duration::ZERO);
+    }
+}
@@ -1,8 +1,12 @@
+use crate::chademo::soc_window::SocWindow;
 use crate::chademo::state::Chademo; //s, ChargerState};
 use crate::error::IndraError;
 use crate::global_state::OperationMode;
 use crate::log_error;
-use crate::pre_charger::PreCharger;
+use crate::pre_charger::{
+    derate::ThermalDerate, energy::EnergyReport, fans::FanStatus, identity::PreIdentity,
+    modules::ModuleCount, status::PreStatus, supervisor::CommsStatus, PreCharger,
+};
 use lazy_static::lazy_static;
 use log::info;
 use serde::Serialize;
@@ -13,6 +17,7 @@ use tokio::sync::{Mutex, RwLock};
 use tokio::time::sleep;
 
 use super::config::MqttConfig;
+use super::meter::MeterReading;
 
 lazy_static! {
     pub static ref CHADEMO_DATA: Arc<RwLock<MqttChademo>> =
@@ -22,6 +27,10 @@ lazy_static! {
 #[derive(Clone, Copy, Serialize, Default, Debug)]
 pub struct MqttChademo {
     pub dc_kw: f32,
+    pub ac_kw: f32,
+    /// Pre conversion efficiency, None at low power
+    pub efficiency: Option<f32>,
+    pub energy: EnergyReport,
     pub soc: f32,
     pub volts: f32,
     pub temp: f32,
@@ -29,26 +38,54 @@ pub struct MqttChademo {
     pub state: OperationMode,
     pub requested_amps: f32,
     pub fan: u8,
+    pub fan_status: FanStatus,
     pub meter_kw: f32,
+    /// Latest meter poll, None while offline
+    pub meter: Option<MeterReading>,
+    pub soc_window: SocWindow,
+    /// Minutes to SoC target
+    pub time_to_target: Option<u16>,
+    pub pre_status: PreStatus,
+    pub pre_identity: PreIdentity,
+    pub pre_comms: CommsStatus,
+    pub pre_modules: ModuleCount,
+    pub thermal: ThermalDerate,
 }
 
 impl MqttChademo {
     pub fn from_pre(&mut self, pre: PreCharger) -> &mut Self {
-        self.dc_kw = pre.ac_power();
+        self.dc_kw = pre.dc_power() * 0.001;
+        self.ac_kw = pre.ac_power() * 0.001;
+        self.efficiency = pre.efficiency();
         self.temp = pre.get_temp();
         self.volts = pre.get_dc_output_volts();
         self.amps = pre.get_dc_output_amps();
         self.fan = pre.get_fan_percentage();
+        self.fan_status = *pre.get_fan();
+        self.pre_status = *pre.get_status();
+        self.pre_identity = *pre.get_identity();
+        self.pre_comms = *pre.get_comms();
+        self.pre_modules = *pre.get_modules();
         self
     }
     pub fn from_chademo(&mut self, chademo: &Chademo) -> &mut Self {
         self.soc = *chademo.soc() as f32;
         self.state = *chademo.state();
         self.requested_amps = chademo.requested_charging_amps();
+        self.soc_window = *chademo.soc_window();
+        self.time_to_target = chademo.time_to_target();
+        self.thermal = *chademo.derate();
         self
     }
-    pub fn from_meter(&mut self, kw: impl Into<f32>) -> &mut Self {
-        self.meter_kw = kw.into();
+    pub fn from_energy(&mut self, energy: EnergyReport) -> &mut Self {
+        self.energy = energy;
+        self
+    }
+    pub fn from_meter(&mut self, reading: Option<MeterReading>) -> &mut Self {
+        if let Some(reading) = reading {
+            self.meter_kw = reading.power * 0.001;
+        }
+        self.meter = reading;
         self
     }
 }
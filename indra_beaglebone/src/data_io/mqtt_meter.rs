@@ -0,0 +1,240 @@
+use super::config::MqttMeterConfig;
+use super::meter::{set_reading, MeterReading};
+use crate::error::IndraError;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde_json::Value;
+use tokio::time::{interval, sleep, Duration, Instant};
+
+const STALE_CHECK: Duration = Duration::from_millis(500);
+
+/// Grid power taken from another system's MQTT messages
+pub struct MqttMeter {
+    pointer: Option<String>,
+    scale: f32,
+    invert: bool,
+    stale: Duration,
+    last: Instant,
+}
+
+impl MqttMeter {
+    pub fn new(config: &MqttMeterConfig, now: Instant) -> Self {
+        Self {
+            pointer: config.pointer.clone(),
+            scale: config.scale,
+            invert: config.invert,
+            stale: Duration::from_millis(config.stale_ms),
+            last: now,
+        }
+    }
+    /// W, positive importing
+    pub fn parse(&self, payload: &[u8]) -> Result<f32, IndraError> {
+        let text =
+            std::str::from_utf8(payload).map_err(|_| IndraError::MeterPayload("not UTF-8"))?;
+        let value = match &self.pointer {
+            None => parse_number(text)?,
+            Some(pointer) => {
+                let json: Value = serde_json::from_str(text).map_err(IndraError::Deserialise)?;
+                match json.pointer(pointer) {
+                    Some(Value::Number(n)) => n.as_f64().unwrap_or(f64::NAN) as f32,
+                    // Home Assistant states are strings
+                    Some(Value::String(s)) => parse_number(s)?,
+                    Some(_) => return Err(IndraError::MeterPayload("not a number")),
+                    None => return Err(IndraError::MeterPayload("pointer not found")),
+                }
+            }
+        };
+        if !value.is_finite() {
+            return Err(IndraError::MeterPayload("not a number"));
+        }
+        let power = value * self.scale;
+        Ok(if self.invert { -power } else { power })
+    }
+    pub fn update(&mut self, payload: &[u8], now: Instant) -> Result<MeterReading, IndraError> {
+        let power = self.parse(payload)?;
+        self.last = now;
+        Ok(MeterReading {
+            power,
+            ..Default::default()
+        })
+    }
+    /// No value within the stale window
+    pub fn is_stale(&self, now: Instant) -> bool {
+        now.duration_since(self.last) > self.stale
+    }
+}
+
+fn parse_number(text: &str) -> Result<f32, IndraError> {
+    text.trim()
+        .parse::<f32>()
+        .map_err(|_| IndraError::MeterPayload("not a number"))
+}
+
+pub async fn mqtt_meter(config: MqttMeterConfig) -> Result<(), IndraError> {
+    log::info!(
+        "Meter from MQTT {}:{} topic:{} pointer:{:?}",
+        config.host,
+        config.port,
+        config.topic,
+        config.pointer
+    );
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(5));
+    options.set_clean_session(true);
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username, password);
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let mut meter = MqttMeter::new(&config, Instant::now());
+    let mut check = interval(STALE_CHECK);
+    let mut online = true;
+    loop {
+        tokio::select! {
+            event = eventloop.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    // clean session, subscribe again after every reconnect
+                    log::info!("Meter MQTT connected");
+                    if let Err(e) = client.try_subscribe(&config.topic, QoS::AtMostOnce) {
+                        log::error!("Meter MQTT subscribe {e:?}");
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    match meter.update(&publish.payload, Instant::now()) {
+                        Ok(reading) => {
+                            log::info!("Meter value {} ", reading.power);
+                            online = true;
+                            set_reading(Some(reading)).await;
+                        }
+                        Err(e) => log::error!("{} {e}", publish.topic),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("Meter MQTT {e:?}");
+                    sleep(Duration::from_secs(1)).await;
+                }
+            },
+            _ = check.tick() => {
+                if online && meter.is_stale(Instant::now()) {
+                    log::error!("Meter MQTT stale");
+                    online = false;
+                    set_reading(None).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data_io::meter::METER;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    fn config(pointer: Option<&str>, scale: f32, invert: bool) -> MqttMeterConfig {
+        MqttMeterConfig {
+            topic: "grid/power".to_string(),
+            pointer: pointer.map(str::to_string),
+            scale,
+            invert,
+            ..Default::default()
+        }
+    }
+    #[test]
+    fn parse_test() {
+        let now = Instant::now();
+        let meter = MqttMeter::new(&config(None, 1.0, false), now);
+        assert_eq!(meter.parse(b" -1500.5\n").unwrap(), -1500.5);
+        assert!(meter.parse(b"unavailable").is_err());
+        assert!(meter.parse(b"NaN").is_err());
+
+        let meter = MqttMeter::new(&config(Some("/grid/kw"), 1000.0, true), now);
+        assert_eq!(meter.parse(br#"{"grid":{"kw":1.5}}"#).unwrap(), -1500.0);
+        assert_eq!(meter.parse(br#"{"grid":{"kw":"-0.25"}}"#).unwrap(), 250.0);
+        assert!(matches!(
+            meter.parse(br#"{"grid":{}}"#),
+            Err(IndraError::MeterPayload("pointer not found"))
+        ));
+        assert!(matches!(
+            meter.parse(b"1.5"),
+            Err(IndraError::MeterPayload(_))
+        ));
+    }
+    #[test]
+    fn stale_test() {
+        let now = Instant::now();
+        let mut meter = MqttMeter::new(&config(None, 1.0, false), now);
+        assert!(!meter.is_stale(now + Duration::from_millis(5000)));
+        assert!(meter.is_stale(now + Duration::from_millis(5001)));
+        let later = now + Duration::from_secs(10);
+        assert_eq!(meter.update(b"42", later).unwrap().power, 42.0);
+        assert!(!meter.is_stale(later + Duration::from_secs(1)));
+    }
+    /// Fixed header type and body of one MQTT packet
+    async fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let header = stream.read_u8().await.ok()?;
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            let byte = stream.read_u8().await.ok()?;
+            len |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await.ok()?;
+        Some((header >> 4, body))
+    }
+    /// Broker stand-in, publishes one message once subscribed
+    async fn mock_broker(topic: &'static str, payload: &'static [u8]) -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            while let Some((packet, body)) = read_packet(&mut stream).await {
+                match packet {
+                    // CONNECT
+                    1 => stream.write_all(&[0x20, 2, 0, 0]).await.unwrap(),
+                    // SUBSCRIBE, ack then publish at QoS 0
+                    8 => {
+                        let suback = [0x90, 3, body[0], body[1], 0];
+                        stream.write_all(&suback).await.unwrap();
+                        let mut publish = vec![0x30, (2 + topic.len() + payload.len()) as u8];
+                        publish.extend((topic.len() as u16).to_be_bytes());
+                        publish.extend(topic.as_bytes());
+                        publish.extend(payload);
+                        stream.write_all(&publish).await.unwrap();
+                    }
+                    // PINGREQ
+                    12 => stream.write_all(&[0xd0, 0]).await.unwrap(),
+                    _ => {}
+                }
+            }
+        });
+        port
+    }
+    #[tokio::test]
+    async fn broker_test() {
+        let port = mock_broker("grid/power", br#"{"power":{"state":"-2300"}}"#).await;
+        let config = MqttMeterConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            ..config(Some("/power/state"), 1.0, true)
+        };
+        tokio::spawn(mqtt_meter(config));
+        let reading = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match *METER.read().await {
+                    Some(reading) if reading.power != 0.0 => break reading,
+                    _ => sleep(Duration::from_millis(50)).await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(reading.power, 2300.0);
+        assert_eq!(reading.volts, None);
+    }
+}
@@ -4,6 +4,7 @@ use crate::{
     error::IndraError,
     global_state::{ChargeParameters, OperationMode},
     log_error,
+    safety::estop,
     // eventbus::{Event, EvtBus},
     // log_error,
     statics::*,
@@ -72,6 +73,10 @@ async fn monitor_pin(pin: Pin, mode_tx: ChademoTx) -> Result<(), sysfs_gpio::Err
         let val = evt.unwrap();
         let opm = OPERATIONAL_MODE.lock().await;
         match (pin.get_pin_num(), val) {
+            (ONOFFPIN, 0) if estop::latched() && boost_held() => {
+                // OnOff pressed while Boost held
+                log_error!("Fault acknowledge gesture", estop::acknowledge());
+            }
             (BOOSTPIN, 0) => {
                 // send state update to toggle charge only
                 let params = ChargeParameters::default();
@@ -101,6 +106,10 @@ async fn monitor_pin(pin: Pin, mode_tx: ChademoTx) -> Result<(), sysfs_gpio::Err
     Ok(())
 }
 
+fn boost_held() -> bool {
+    matches!(Pin::new(BOOSTPIN).get_value(), Ok(0))
+}
+
 pub async fn panel_event_listener(mut led_rx: LedRx, mode_tx: ChademoTx) -> Result<(), IndraError> {
     log::info!("Starting panel_event_listener {}", tokio::task::id());
     let dev = I2cdev::new("/dev/i2c-2").expect("Cannot access /dev/i2c-2");
@@ -0,0 +1,182 @@
+use super::config::{WearConfig, APP_CONFIG};
+use crate::POOL;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::chrono::Utc, FromRow};
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+use sysfs_gpio::Pin;
+
+/// DC amps at or above this when opening counts as switching under load
+const LOAD_AMPS: f32 = 1.0;
+
+lazy_static::lazy_static! {
+    /// Close time of each device, open devices are absent
+    static ref CLOSED: Mutex<HashMap<Device, Instant>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Device {
+    C1,
+    C2,
+    PreAc,
+    PlugLock,
+}
+
+impl Device {
+    pub const ALL: [Device; 4] = [Device::C1, Device::C2, Device::PreAc, Device::PlugLock];
+    pub fn name(&self) -> &'static str {
+        match self {
+            Device::C1 => "c1",
+            Device::C2 => "c2",
+            Device::PreAc => "pre_ac",
+            Device::PlugLock => "plug_lock",
+        }
+    }
+}
+
+/// One close -> open cycle
+#[derive(Debug, Clone, Copy)]
+pub struct OpenEvent {
+    pub device: Device,
+    pub amps: f32,
+    pub on_secs: f64,
+}
+
+impl OpenEvent {
+    pub fn under_load(&self) -> bool {
+        self.amps.abs() >= LOAD_AMPS
+    }
+}
+
+#[derive(Clone, FromRow, Debug, Serialize, Deserialize)]
+pub struct WearRecord {
+    pub device: String,
+    pub cycles: i64,
+    pub load_switches: i64,
+    pub on_secs: f64,
+}
+
+#[derive(Clone, FromRow, Debug, Serialize, Deserialize)]
+pub struct LoadSwitchRow {
+    pub id: u32,
+    pub timestamp: chrono::DateTime<Utc>,
+    pub device: String,
+    pub amps: f32,
+}
+
+/// Counters with any maintenance thresholds exceeded
+#[derive(Clone, Debug, Serialize)]
+pub struct WearStatus {
+    #[serde(flatten)]
+    pub record: WearRecord,
+    pub warnings: Vec<String>,
+}
+
+impl WearStatus {
+    pub fn new(record: WearRecord, config: &WearConfig) -> Self {
+        let warnings = warnings(&record, config);
+        Self { record, warnings }
+    }
+}
+
+fn warnings(record: &WearRecord, config: &WearConfig) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if record.cycles >= config.max_cycles as i64 {
+        warnings.push(format!(
+            "{} cycles reached {} limit",
+            record.cycles, config.max_cycles
+        ));
+    }
+    if record.load_switches >= config.max_load_switches as i64 {
+        warnings.push(format!(
+            "{} load switches reached {} limit",
+            record.load_switches, config.max_load_switches
+        ));
+    }
+    let hours = record.on_secs / 3600.0;
+    if hours >= config.max_on_hours as f64 {
+        warnings.push(format!(
+            "{hours:.0} hours on reached {} limit",
+            config.max_on_hours
+        ));
+    }
+    warnings
+}
+
+/// Sets a contactor / solenoid pin and records the transition, amps is the measured current
+pub fn switch(device: Device, pin: &Pin, value: u8, amps: f32) -> Result<(), sysfs_gpio::Error> {
+    pin.set_value(value)?;
+    match value {
+        0 => opened(device, amps),
+        _ => closed(device),
+    }
+    Ok(())
+}
+
+pub fn closed(device: Device) {
+    if let Ok(mut closed) = CLOSED.lock() {
+        closed.entry(device).or_insert_with(Instant::now);
+    }
+}
+
+/// Counts a cycle if the device was closed, amps is the Pre DC current at open
+pub fn opened(device: Device, amps: f32) {
+    let Some(since) = CLOSED.lock().ok().and_then(|mut c| c.remove(&device)) else {
+        return; // already open
+    };
+    let event = OpenEvent {
+        device,
+        amps,
+        on_secs: since.elapsed().as_secs_f64(),
+    };
+    if event.under_load() {
+        log::error!("{} opened under load {amps:.1}A", device.name());
+    }
+    tokio::spawn(async move {
+        let Some(db) = POOL.get() else { return };
+        if let Err(e) = db.add_wear(&event).await {
+            log::error!("db wear {e:?}");
+            return;
+        }
+        if let Ok(record) = db.get_wear_record(device.name()).await {
+            for warning in warnings(&record, &APP_CONFIG.wear) {
+                log::warn!("{} maintenance due: {warning}", device.name());
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn warnings_test() {
+        let config = WearConfig {
+            max_cycles: 100,
+            max_load_switches: 2,
+            max_on_hours: 10.0,
+        };
+        let mut record = WearRecord {
+            device: Device::C1.name().into(),
+            cycles: 99,
+            load_switches: 1,
+            on_secs: 35_999.0,
+        };
+        assert!(warnings(&record, &config).is_empty());
+        record.cycles = 100;
+        assert_eq!(warnings(&record, &config).len(), 1);
+        record.load_switches = 2;
+        record.on_secs = 36_000.0;
+        assert_eq!(warnings(&record, &config).len(), 3);
+    }
+    #[test]
+    fn under_load_test() {
+        let event = |amps| OpenEvent {
+            device: Device::C2,
+            amps,
+            on_secs: 1.0,
+        };
+        assert!(!event(0.5).under_load());
+        assert!(event(1.0).under_load());
+        assert!(event(-12.0).under_load());
+    }
+}
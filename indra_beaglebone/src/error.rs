@@ -1,3 +1,7 @@
+use crate::{
+    data_io::modbus::ExceptionCode,
+    pre_charger::{sdo::AbortCode, Register},
+};
 use rumqttc::ClientError;
 use std::net::AddrParseError;
 #[allow(dead_code)]
@@ -25,6 +29,21 @@ pub enum IndraError {
     Timeout,
     CanTxError((std::io::Error, u8)),
     MeterOffline,
+    BatteryIncompatible(u16, u16),
+    EstopActive,
+    FaultLatched,
+    ActuatorFeedback(&'static str, bool),
+    StartupFault,
+    SdoAbort(Register, AbortCode),
+    SdoProtocol(u8),
+    PreCommsLost,
+    FanBusy,
+    ModbusException(ExceptionCode),
+    ModbusCrc,
+    ModbusInvalid(&'static str),
+    ModbusIo(std::io::Error),
+    SerialOpen(tokio_serial::Error),
+    MeterPayload(&'static str),
     // FileAccess(_),
     // I2cWriteError,
 }
@@ -55,6 +74,28 @@ impl std::fmt::Display for IndraError {
             Timeout => write!(f, "Timeout"),
             CanTxError((e, n)) => write!(f, "CanTxError #{n} {e:?}"),
             MeterOffline => write!(f, "Meter is offline"),
+            BatteryIncompatible(target, min) => write!(
+                f,
+                "Battery incompatible: target {target}V / minimum {min}V outside Pre range"
+            ),
+            EstopActive => write!(f, "E-stop input still active"),
+            FaultLatched => write!(f, "Latched fault, acknowledge before use"),
+            ActuatorFeedback(name, closed) => write!(
+                f,
+                "{name} feedback did not report {} within timeout",
+                if *closed { "closed" } else { "open" }
+            ),
+            StartupFault => write!(f, "Startup self-check failed or incomplete"),
+            SdoAbort(register, code) => write!(f, "Pre SDO abort {register:?} {code}"),
+            SdoProtocol(cmd) => write!(f, "Pre SDO unexpected command {cmd:02x}"),
+            PreCommsLost => write!(f, "Pre not responding"),
+            FanBusy => write!(f, "Fan in use by an active session or test"),
+            ModbusException(code) => write!(f, "Modbus exception {code}"),
+            ModbusCrc => write!(f, "Modbus CRC mismatch"),
+            ModbusInvalid(reason) => write!(f, "Invalid Modbus frame, {reason}"),
+            ModbusIo(e) => write!(f, "Modbus IO failed {e:?}"),
+            SerialOpen(e) => write!(f, "Serial port open failed {e:?}"),
+            MeterPayload(reason) => write!(f, "Meter payload {reason}"),
         }
     }
 }
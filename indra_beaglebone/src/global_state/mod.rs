@@ -1,4 +1,3 @@
-use crate::MAX_AMPS;
 use serde::{Deserialize, Serialize};
 use std::ops::Deref;
 
@@ -33,11 +32,9 @@ pub struct ChargeParameters {
 }
 
 impl ChargeParameters {
-    pub fn get_amps(&self) -> u8 {
-        match self.amps {
-            Some(amps) => amps,
-            None => MAX_AMPS,
-        }
+    /// None uses all the Pre can supply
+    pub fn get_amps(&self) -> Option<u8> {
+        self.amps
     }
     pub fn set_amps(&mut self, limit: u8) -> Self {
         self.amps = Some(limit);
@@ -20,6 +20,7 @@ mod error;
 mod global_state;
 mod macros;
 mod pre_charger;
+mod safety;
 mod scheduler;
 
 const MAX_SOC: u8 = 90;
@@ -41,9 +42,6 @@ static POOL: OnceCell<Database> = OnceCell::const_new();
  *      Config
  *          Min/max V2H SoC - web ui
  *
- *      eStop
- *          Detect input pin?
- *
  */
 #[tokio::main]
 async fn main() -> Result<(), &'static str> {
@@ -69,9 +67,25 @@ async fn main() -> Result<(), &'static str> {
     let app_config = &APP_CONFIG.clone();
 
     let _pca9552_reset = state::pin_init_out_high(state::RESETPCAPIN).unwrap();
-    let _master = state::pin_init_out_high(state::MASTERCONTACTOR).unwrap();
+    if let Err(e) = safety::feedback::init(&app_config.feedback) {
+        log::error!("Feedback inputs unavailable, actuators cannot be verified {e}");
+    }
+    safety::startup::self_check(&app_config.feedback).await;
+    pre_charger::status::init(&app_config.pre_status);
+    let _master = if safety::estop::is_latched().await {
+        log::error!("Latched fault - master contactor held open until acknowledged");
+        state::pin_init_out_low(state::MASTERCONTACTOR).unwrap()
+    } else {
+        state::pin_init_out_high(state::MASTERCONTACTOR).unwrap()
+    };
+
+    tokio::spawn(safety::estop::estop_monitor(
+        app_config.estop.clone(),
+        mode_tx.clone(),
+    ));
 
-    tokio::spawn(meter::meter(app_config.meter.clone())); // rtu over tcp SDM230 modbus meter
+    tokio::spawn(pre_charger::fans::tach_monitor(app_config.fan.clone()));
+    tokio::spawn(meter::meter(app_config.meter.clone())); // grid meter, Modbus or MQTT
     tokio::spawn(panel::panel_event_listener(led_rx, mode_tx.clone()));
     tokio::spawn(scheduler::init(events_rx, mode_tx.clone()));
     tokio::spawn(api::run(events_tx, mode_tx.clone()));
@@ -0,0 +1,89 @@
+use crate::data_io::config::DerateConfig;
+use serde::Serialize;
+
+#[derive(Serialize, Default, Debug, Clone, Copy, PartialEq)]
+pub enum DerateState {
+    #[default]
+    Full,
+    Derating,
+    /// Hot enough that no current is allowed
+    Stopped,
+}
+
+/// Current limit from Pre temperature, applies to charge & discharge
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct ThermalDerate {
+    pub state: DerateState,
+    /// Fraction of full current allowed
+    pub factor: f32,
+}
+
+impl Default for ThermalDerate {
+    fn default() -> Self {
+        Self {
+            state: DerateState::Full,
+            factor: 1.0,
+        }
+    }
+}
+
+impl ThermalDerate {
+    /// Full below config.full_below, linear down to 0 at config.stop_above
+    pub fn from_temp(temp: f32, config: &DerateConfig) -> Self {
+        if temp <= config.full_below {
+            return Self::default();
+        }
+        if temp >= config.stop_above {
+            return Self {
+                state: DerateState::Stopped,
+                factor: 0.0,
+            };
+        }
+        Self {
+            state: DerateState::Derating,
+            factor: (config.stop_above - temp) / (config.stop_above - config.full_below),
+        }
+    }
+    /// Lowers the factor for a cause other than temperature
+    pub fn cap(self, factor: f32) -> Self {
+        if factor >= self.factor {
+            return self;
+        }
+        let state = match factor <= 0.0 {
+            true => DerateState::Stopped,
+            false => DerateState::Derating,
+        };
+        Self {
+            state,
+            factor: factor.max(0.0),
+        }
+    }
+    pub fn limit(&self, amps: f32) -> f32 {
+        amps * self.factor
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn derate_test() {
+        let config = DerateConfig {
+            full_below: 60.0,
+            stop_above: 80.0,
+        };
+        assert_eq!(
+            ThermalDerate::from_temp(25.0, &config),
+            ThermalDerate::default()
+        );
+        let derate = ThermalDerate::from_temp(70.0, &config);
+        assert_eq!(derate.state, DerateState::Derating);
+        assert_eq!(derate.limit(-16.0), -8.0);
+        let derate = ThermalDerate::from_temp(85.0, &config);
+        assert_eq!(derate.state, DerateState::Stopped);
+        assert_eq!(derate.limit(16.0), 0.0);
+        let derate = ThermalDerate::default().cap(0.5);
+        assert_eq!(derate.state, DerateState::Derating);
+        assert_eq!(derate.cap(0.8).factor, 0.5);
+    }
+}
@@ -0,0 +1,219 @@
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use serde::{ser::SerializeStruct, Serialize};
+use sqlx::FromRow;
+use std::{sync::Mutex, time::Duration};
+
+/// Either side below this is too small for a meaningful sample ratio
+const MIN_WATTS: f32 = 100.0;
+/// Input energy below this is too small for a meaningful total ratio
+const MIN_WH: f64 = 10.0;
+
+lazy_static::lazy_static! {
+    pub static ref ENERGY: Mutex<EnergyAccount> = Mutex::new(EnergyAccount::default());
+}
+
+/// Conversion efficiency, output over input in the direction of power flow
+///
+/// AC watts are positive drawn from the grid, DC watts positive into the EV.
+pub fn efficiency(ac_watts: f32, dc_watts: f32) -> Option<f32> {
+    if ac_watts.abs() < MIN_WATTS || dc_watts.abs() < MIN_WATTS {
+        return None;
+    }
+    match (ac_watts > 0.0, dc_watts > 0.0) {
+        (true, true) => Some(dc_watts / ac_watts),
+        (false, false) => Some(ac_watts / dc_watts),
+        // mid reversal
+        _ => None,
+    }
+}
+
+/// Energy through each side of the Pre, split by direction
+#[derive(FromRow, Default, Debug, Clone, Copy, PartialEq)]
+pub struct EnergyTotals {
+    /// Grid -> Pre
+    pub ac_in_wh: f64,
+    /// Pre -> grid
+    pub ac_out_wh: f64,
+    /// EV -> Pre
+    pub dc_in_wh: f64,
+    /// Pre -> EV
+    pub dc_out_wh: f64,
+}
+
+impl EnergyTotals {
+    pub fn add(&mut self, ac_watts: f32, dc_watts: f32, dt: Duration) {
+        let hours = dt.as_secs_f64() / 3600.0;
+        let ac_wh = ac_watts as f64 * hours;
+        let dc_wh = dc_watts as f64 * hours;
+        match ac_wh.is_sign_negative() {
+            true => self.ac_out_wh -= ac_wh,
+            false => self.ac_in_wh += ac_wh,
+        }
+        match dc_wh.is_sign_negative() {
+            true => self.dc_in_wh -= dc_wh,
+            false => self.dc_out_wh += dc_wh,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+    /// Grid to EV
+    pub fn charge_efficiency(&self) -> Option<f64> {
+        ratio(self.dc_out_wh, self.ac_in_wh)
+    }
+    /// EV to grid
+    pub fn discharge_efficiency(&self) -> Option<f64> {
+        ratio(self.ac_out_wh, self.dc_in_wh)
+    }
+    /// Conversion losses charging then discharging, excludes the EV battery
+    pub fn round_trip(&self) -> Option<f64> {
+        Some(self.charge_efficiency()? * self.discharge_efficiency()?)
+    }
+    /// Energy lost in conversion, includes Pre standby draw
+    pub fn loss_wh(&self) -> f64 {
+        self.ac_in_wh + self.dc_in_wh - self.ac_out_wh - self.dc_out_wh
+    }
+}
+
+fn ratio(output: f64, input: f64) -> Option<f64> {
+    match input < MIN_WH {
+        true => None,
+        false => Some(output / input),
+    }
+}
+
+impl Serialize for EnergyTotals {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("EnergyTotals", 8)?;
+        s.serialize_field("ac_in_wh", &self.ac_in_wh)?;
+        s.serialize_field("ac_out_wh", &self.ac_out_wh)?;
+        s.serialize_field("dc_in_wh", &self.dc_in_wh)?;
+        s.serialize_field("dc_out_wh", &self.dc_out_wh)?;
+        s.serialize_field("loss_wh", &self.loss_wh())?;
+        s.serialize_field("charge_efficiency", &self.charge_efficiency())?;
+        s.serialize_field("discharge_efficiency", &self.discharge_efficiency())?;
+        s.serialize_field("round_trip", &self.round_trip())?;
+        s.end()
+    }
+}
+
+/// One Pre power up to power down
+#[derive(Debug, Clone, Copy)]
+pub struct EnergySession {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub totals: EnergyTotals,
+}
+
+/// Sessions summed by local start date
+#[derive(FromRow, Serialize, Debug, Clone)]
+pub struct EnergyDay {
+    pub day: String,
+    pub sessions: i64,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub totals: EnergyTotals,
+}
+
+#[derive(Serialize, Default, Debug, Clone, Copy)]
+pub struct EnergyReport {
+    pub session_start: Option<DateTime<Utc>>,
+    pub session: EnergyTotals,
+    pub today: EnergyTotals,
+}
+
+/// Running totals for the current session & local day
+#[derive(Default, Debug)]
+pub struct EnergyAccount {
+    session_start: Option<DateTime<Utc>>,
+    session: EnergyTotals,
+    date: Option<NaiveDate>,
+    today: EnergyTotals,
+}
+
+impl EnergyAccount {
+    pub fn add(&mut self, ac_watts: f32, dc_watts: f32, dt: Duration, now: DateTime<Local>) {
+        let date = now.date_naive();
+        if self.date != Some(date) {
+            self.date = Some(date);
+            self.today = EnergyTotals::default();
+        }
+        self.session.add(ac_watts, dc_watts, dt);
+        self.today.add(ac_watts, dc_watts, dt);
+    }
+    /// Returns the previous session if it was never ended
+    pub fn start_session(&mut self, now: DateTime<Utc>) -> Option<EnergySession> {
+        let previous = self.end_session(now);
+        self.session_start = Some(now);
+        previous
+    }
+    pub fn end_session(&mut self, now: DateTime<Utc>) -> Option<EnergySession> {
+        let start = self.session_start.take()?;
+        let totals = std::mem::take(&mut self.session);
+        Some(EnergySession {
+            start,
+            end: now,
+            totals,
+        })
+    }
+    pub fn report(&self) -> EnergyReport {
+        EnergyReport {
+            session_start: self.session_start,
+            session: self.session,
+            today: self.today,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    #[test]
+    fn efficiency_test() {
+        assert_eq!(efficiency(5000.0, 4500.0), Some(0.9));
+        assert_eq!(efficiency(-4000.0, -5000.0), Some(0.8));
+        assert_eq!(efficiency(50.0, 0.0), None);
+        assert_eq!(efficiency(2000.0, -1500.0), None);
+    }
+    #[test]
+    fn totals_test() {
+        let hour = Duration::from_secs(3600);
+        let mut totals = EnergyTotals::default();
+        assert_eq!(totals.round_trip(), None);
+        totals.add(5000.0, 4500.0, hour);
+        assert_eq!(totals.charge_efficiency(), Some(0.9));
+        assert_eq!(totals.discharge_efficiency(), None);
+        totals.add(-4000.0, -5000.0, hour);
+        assert_eq!(totals.dc_in_wh, 5000.0);
+        assert_eq!(totals.ac_out_wh, 4000.0);
+        assert!((totals.round_trip().unwrap() - 0.72).abs() < 1e-9);
+        assert_eq!(totals.loss_wh(), 1500.0);
+    }
+    #[test]
+    fn account_test() {
+        let mut account = EnergyAccount::default();
+        let second = Duration::from_secs(1);
+        let evening = Local.with_ymd_and_hms(2024, 5, 1, 23, 59, 0).unwrap();
+        assert!(account.start_session(Utc::now()).is_none());
+        account.add(3600.0, 3240.0, second, evening);
+        assert_eq!(account.report().today.ac_in_wh, 1.0);
+
+        // day rolls over, session continues
+        account.add(
+            3600.0,
+            3240.0,
+            second,
+            evening + chrono::Duration::minutes(2),
+        );
+        let report = account.report();
+        assert_eq!(report.today.ac_in_wh, 1.0);
+        assert_eq!(report.session.ac_in_wh, 2.0);
+
+        let session = account.start_session(Utc::now()).unwrap();
+        assert_eq!(session.totals.dc_out_wh, 1.8);
+        assert!(account.report().session.is_empty());
+        assert!(account.end_session(Utc::now()).is_some());
+        assert!(account.end_session(Utc::now()).is_none());
+    }
+}
@@ -1,11 +1,44 @@
-use super::pwm::Pwm;
-use crate::log_error;
-use std::time::Duration;
-use tokio::time::Instant;
+use super::pwm::{self, Pwm};
+use crate::{
+    data_io::{
+        config::{FanConfig, TachConfig, APP_CONFIG},
+        mqtt::CHADEMO_DATA,
+    },
+    error::IndraError,
+};
+use futures::StreamExt;
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use sysfs_gpio::{Direction, Edge, Pin};
+use tokio::{
+    sync::Mutex,
+    time::{sleep, Instant},
+};
 
-// Setpoints
-const FAN100: f32 = 70.0;
-const FAN0: f32 = 50.0;
+lazy_static::lazy_static! {
+    /// Only owner of the fan PWM, shared by the idle loop, pre_thread & fan tests
+    pub static ref FAN: Arc<Mutex<Fan>> = Arc::new(Mutex::new(Fan::new(
+        Pwm::from_config(&APP_CONFIG.pwm),
+        &APP_CONFIG.fan
+    )));
+}
+
+/// Tach edges counted by tach_monitor
+static TACH_PULSES: AtomicU64 = AtomicU64::new(0);
+/// Time for the fan to reach speed before a stall is flagged
+const SPIN_UP: Duration = Duration::from_secs(5);
+/// Shortest window rpm is measured over
+const RPM_WINDOW: Duration = Duration::from_secs(1);
+/// How often a fan test checks for a session starting
+const TEST_POLL: Duration = Duration::from_millis(100);
+/// Set while a fan test is running
+static FAN_TESTING: AtomicBool = AtomicBool::new(false);
 
 #[derive(Default, Copy, Clone, Debug)]
 struct Duty {
@@ -34,61 +67,328 @@ impl Into<u8> for Duty {
     }
 }
 
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum FanFault {
+    /// PWM output could not be driven
+    Pwm,
+    /// Tach below min_rpm while driven
+    Stalled,
+}
+
+#[derive(Serialize, Default, Debug, Clone, Copy, PartialEq)]
+pub struct FanStatus {
+    /// None without a tach input
+    pub rpm: Option<u32>,
+    pub fault: Option<FanFault>,
+}
+
+/// Result of a manual fan run
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct FanTest {
+    pub duty: u8,
+    #[serde(flatten)]
+    pub status: FanStatus,
+}
+
+/// Duty from temperature by curve, or PI to a target temperature when configured
+#[derive(Debug)]
+struct FanControl {
+    config: FanConfig,
+    integral: f32,
+    last: Option<Instant>,
+}
+
+impl FanControl {
+    fn duty(&mut self, temp: f32) -> u8 {
+        let Some(pi) = self.config.pi else {
+            return curve_duty(&self.config.curve, temp);
+        };
+        let now = Instant::now();
+        let dt = self
+            .last
+            .replace(now)
+            .map_or(0.0, |last| now.duration_since(last).as_secs_f32());
+        let error = temp - pi.target;
+        self.integral = (self.integral + pi.ki * error * dt).clamp(0.0, 100.0);
+        (pi.kp * error + self.integral).clamp(0.0, 100.0) as u8
+    }
+    /// Temperature calls for the fan, above the PI target or where the curve reaches min_duty
+    fn needed(&self, temp: f32) -> bool {
+        match self.config.pi {
+            Some(pi) => temp > pi.target,
+            None => curve_duty(&self.config.curve, temp) >= self.config.min_duty,
+        }
+    }
+}
+
+/// Linear between (°C, duty) points, held at the end points, full duty without a curve
+fn curve_duty(curve: &[(f32, u8)], temp: f32) -> u8 {
+    let (Some(first), Some(last)) = (curve.first(), curve.last()) else {
+        return 100;
+    };
+    if temp <= first.0 {
+        return first.1.min(100);
+    }
+    curve
+        .windows(2)
+        .find(|w| temp <= w[1].0)
+        .map(|w| {
+            let ((t0, d0), (t1, d1)) = (w[0], w[1]);
+            let span = (t1 - t0).max(f32::EPSILON);
+            (d0 as f32 + (d1 as f32 - d0 as f32) * (temp - t0) / span) as u8
+        })
+        .unwrap_or(last.1)
+        .min(100)
+}
+
+fn rpm(pulses: u64, window: Duration, pulses_per_rev: u8) -> u32 {
+    let revs = pulses as f32 / pulses_per_rev.max(1) as f32;
+    (revs * 60.0 / window.as_secs_f32()) as u32
+}
+
 #[derive(Debug)]
 pub struct Fan {
     duty: Duty,
-    pwm: Pwm,
+    /// When the fan last started from off, duty changes while running don't reset it
+    running: Option<Instant>,
+    pwm: Option<Pwm>,
+    control: FanControl,
+    tach: Option<TachConfig>,
+    /// (pulse count, time) at the start of the rpm window
+    window: (u64, Instant),
+    status: FanStatus,
 }
 
 impl Fan {
-    pub fn new(pwm: Pwm) -> Self {
-        if pwm.export().is_err() {
-            panic!("PWM EXPORT FAIL")
-        }
-        if pwm.enable(true).is_err() {
-            if pwm.enable(false).is_err() {
-                log::warn!("PWM ENABLE RETRY")
-            } else if pwm.enable(true).is_err() {
-                panic!("PWM ENABLE FAILED")
+    /// PWM failures are reported as FanFault::Pwm, fans are then left as they are
+    pub fn new(pwm: pwm::Result<Pwm>, config: &FanConfig) -> Self {
+        let pwm = pwm.and_then(|pwm| {
+            pwm.export()?;
+            if pwm.enable(true).is_err() {
+                log::warn!("PWM enable retry");
+                pwm.enable(false)?;
+                pwm.enable(true)?;
             }
-        }
+            Ok(pwm)
+        });
+        let (pwm, fault) = match pwm {
+            Ok(pwm) => (Some(pwm), None),
+            Err(e) => {
+                log::error!("Fan PWM unavailable {e}");
+                (None, Some(FanFault::Pwm))
+            }
+        };
         Self {
             duty: Duty::default(),
+            running: None,
             pwm,
+            control: FanControl {
+                config: config.clone(),
+                integral: 0.0,
+                last: None,
+            },
+            tach: config.tach.clone(),
+            window: (TACH_PULSES.load(Ordering::Relaxed), Instant::now()),
+            status: FanStatus { rpm: None, fault },
         }
     }
     pub fn stop(&mut self) {
-        let _ = self.pwm.set_duty(0);
-        log_error!("PWM disable", self.pwm.enable(false));
+        self.duty = Duty::new(0);
+        self.running = None;
+        if let Some(pwm) = self.pwm.as_ref() {
+            let _ = pwm.set_duty(0);
+            if let Err(e) = pwm.enable(false) {
+                log::error!("PWM disable {e}");
+            }
+        }
     }
+    /// Duty from temperature, falling duty overruns before it drops
     pub fn update(&mut self, temp: f32) -> u8 {
-        let elapsed = self.duty.elapsed(Duration::from_secs(20));
-        let new_duty = Duty::new(temp_to_duty(temp));
-
-        if self.duty.val != new_duty.val {
-            if self.duty.val > new_duty.val && !elapsed {
-                // falling -> overrun fan for 20 seconds
+        let duty = self.control.duty(temp);
+        self.apply(duty)
+    }
+    /// Pre is hot enough to need the fan while idle
+    pub fn needed(&self, temp: f32) -> bool {
+        self.control.needed(temp)
+    }
+    /// Fans off after the overrun
+    pub fn off(&mut self) -> u8 {
+        self.apply(0)
+    }
+    fn apply(&mut self, duty: u8) -> u8 {
+        let elapsed = self
+            .duty
+            .elapsed(Duration::from_secs(self.control.config.overrun_secs));
+        if self.duty.val != duty {
+            if self.duty.val > duty && !elapsed {
+                // falling -> overrun fan
                 return self.duty.val;
             }
-
-            self.duty = new_duty; // pwm noise below 20%??
-            if self.duty.val < 20 {
-                self.stop()
-            } else {
-                log_error!("PWM enable", self.pwm.enable(true));
-                if let Err(e) = self.pwm.set_duty(self.duty.val) {
-                    log::error!("Duty update error {e}")
-                }
-            }
+            self.set(duty);
         };
         self.duty.val
     }
+    /// Drives duty now, below min_duty is off
+    fn set(&mut self, duty: u8) {
+        if duty < self.control.config.min_duty {
+            self.stop();
+            return;
+        }
+        self.duty = Duty::new(duty.min(100));
+        self.running.get_or_insert_with(Instant::now);
+        let Some(pwm) = self.pwm.as_ref() else {
+            return;
+        };
+        let result = pwm.enable(true).and_then(|_| pwm.set_duty(self.duty.val));
+        if let Err(e) = result {
+            log::error!("Duty update error {e}");
+            self.status.fault = Some(FanFault::Pwm);
+        }
+    }
+    /// Measures rpm & checks for a stall, rpm is updated once per window
+    pub fn status(&mut self) -> FanStatus {
+        let Some(tach) = self.tach.as_ref() else {
+            return self.status;
+        };
+        let (start, since) = self.window;
+        if since.elapsed() < RPM_WINDOW {
+            return self.status;
+        }
+        let pulses = TACH_PULSES.load(Ordering::Relaxed);
+        let rpm = rpm(pulses - start, since.elapsed(), tach.pulses_per_rev);
+        self.window = (pulses, Instant::now());
+        self.status.rpm = Some(rpm);
+
+        let driven = self.running.is_some_and(|since| since.elapsed() > SPIN_UP);
+        match (driven && rpm < tach.min_rpm, self.status.fault) {
+            (true, None) => {
+                log::error!("Fan stalled {rpm}rpm at {}%", self.duty.val);
+                self.status.fault = Some(FanFault::Stalled);
+            }
+            (false, Some(FanFault::Stalled)) if driven => {
+                log::info!("Fan running {rpm}rpm");
+                self.status.fault = None;
+            }
+            _ => (),
+        }
+        self.status
+    }
+}
+
+/// Counts tach edges while the fan is configured with one
+pub async fn tach_monitor(config: FanConfig) -> Result<(), IndraError> {
+    let Some(tach) = config.tach else {
+        log::warn!("Fan tach input not configured");
+        return Ok(());
+    };
+    log::info!(
+        "Starting fan tach {} on gpio{}",
+        tokio::task::id(),
+        tach.pin
+    );
+    let pin = Pin::new(tach.pin);
+    pin.export()
+        .map_err(|_| IndraError::PinInitError(tach.pin))?;
+    pin.set_direction(Direction::In)
+        .map_err(|e| IndraError::PinAccess(e))?;
+    pin.set_edge(Edge::RisingEdge)
+        .map_err(|e| IndraError::PinAccess(e))?;
+    let mut edges = pin
+        .get_value_stream()
+        .map_err(|e| IndraError::PinAccess(e))?;
+    while let Some(edge) = edges.next().await {
+        match edge {
+            Ok(_) => {
+                TACH_PULSES.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => log::error!("Fan tach {e:?}"),
+        }
+    }
+    Err(IndraError::Error)
+}
+
+/// Runs the fan at duty long enough to check the tach, refused during a session
+///
+/// FAN is only locked briefly so a session starting mid test takes the fan over,
+/// the test then ends without touching it.
+pub async fn fan_test(duty: u8, config: &FanConfig) -> Result<FanTest, IndraError> {
+    if !session_inactive().await || FAN_TESTING.swap(true, Ordering::AcqRel) {
+        return Err(IndraError::FanBusy);
+    }
+    let _guard = TestGuard;
+    let duty = duty.max(config.min_duty).min(100);
+    FAN.lock().await.set(duty);
+    hold(SPIN_UP + RPM_WINDOW).await?;
+    {
+        // fresh window so the spin-up doesn't drag the reading down
+        let mut fan = FAN.lock().await;
+        fan.window = (TACH_PULSES.load(Ordering::Relaxed), Instant::now());
+    }
+    hold(RPM_WINDOW).await?;
+    let mut fan = FAN.lock().await;
+    if !session_inactive().await {
+        return Err(IndraError::FanBusy);
+    }
+    let status = fan.status();
+    fan.stop();
+    log::info!("Fan test {duty}% {status:?}");
+    Ok(FanTest { duty, status })
+}
+
+/// Clears FAN_TESTING however the test ends, including a dropped request
+struct TestGuard;
+
+impl Drop for TestGuard {
+    fn drop(&mut self) {
+        FAN_TESTING.store(false, Ordering::Release);
+    }
+}
+
+async fn session_inactive() -> bool {
+    CHADEMO_DATA.read().await.state.is_inactive()
 }
 
-fn temp_to_duty(value: impl Into<f32>) -> u8 {
-    let old_range = FAN100 - FAN0;
-    let new_range = 100.0 - 0.1;
-    let value: f32 = value.into();
-    let duty = (((value - FAN0) * new_range / old_range) + 0.1) as u8;
-    duty.min(100)
+/// Sleeps for period, gives up if a session starts meanwhile
+async fn hold(period: Duration) -> Result<(), IndraError> {
+    let end = Instant::now() + period;
+    while Instant::now() < end {
+        sleep(TEST_POLL.min(end.saturating_duration_since(Instant::now()))).await;
+        if !session_inactive().await {
+            log::warn!("Fan test aborted, session started");
+            return Err(IndraError::FanBusy);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn curve_test() {
+        let curve = [(50.0, 0), (70.0, 100)];
+        assert_eq!(curve_duty(&curve, 25.0), 0);
+        assert_eq!(curve_duty(&curve, 60.0), 50);
+        assert_eq!(curve_duty(&curve, 90.0), 100);
+        let curve = [(40.0, 20), (60.0, 40), (70.0, 100)];
+        assert_eq!(curve_duty(&curve, 50.0), 30);
+        assert_eq!(curve_duty(&curve, 65.0), 70);
+        assert_eq!(curve_duty(&[], 20.0), 100);
+    }
+    #[test]
+    fn needed_test() {
+        // 20% min duty is reached at 54°C on the default curve
+        let control = FanControl {
+            config: FanConfig::default(),
+            integral: 0.0,
+            last: None,
+        };
+        assert!(!control.needed(53.0));
+        assert!(control.needed(55.0));
+    }
+    #[test]
+    fn rpm_test() {
+        assert_eq!(rpm(100, Duration::from_secs(1), 2), 3000);
+        assert_eq!(rpm(0, Duration::from_secs(1), 2), 0);
+    }
 }
@@ -0,0 +1,100 @@
+use serde::Serialize;
+
+const ID_LEN: usize = 32;
+
+/// Fixed capacity string so PreCharger stays Copy, longer values are truncated
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct IdString {
+    buf: [u8; ID_LEN],
+    len: u8,
+}
+
+impl IdString {
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.buf[..self.len as usize]).unwrap_or_default()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for IdString {
+    fn default() -> Self {
+        Self {
+            buf: [0; ID_LEN],
+            len: 0,
+        }
+    }
+}
+
+impl From<&str> for IdString {
+    fn from(value: &str) -> Self {
+        let mut end = value.len().min(ID_LEN);
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut id = Self::default();
+        id.buf[..end].copy_from_slice(&value.as_bytes()[..end]);
+        id.len = end as u8;
+        id
+    }
+}
+
+impl std::fmt::Display for IdString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl std::fmt::Debug for IdString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl Serialize for IdString {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Pre 0x1008/0x1009/0x100a strings, read once at init
+#[derive(Serialize, Default, Debug, Clone, Copy, PartialEq)]
+pub struct PreIdentity {
+    pub device_name: IdString,
+    pub hardware_version: IdString,
+    pub software_version: IdString,
+}
+
+impl PreIdentity {
+    pub fn is_empty(&self) -> bool {
+        self.device_name.is_empty()
+            && self.hardware_version.is_empty()
+            && self.software_version.is_empty()
+    }
+}
+
+impl std::fmt::Display for PreIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} hw: {} sw: {}",
+            self.device_name, self.hardware_version, self.software_version
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn id_string_test() {
+        let id = IdString::from("PRE-30k");
+        assert_eq!(id.as_str(), "PRE-30k");
+        assert_eq!(serde_json::to_string(&id).unwrap(), r#""PRE-30k""#);
+        assert!(IdString::default().is_empty());
+        // truncated on a char boundary
+        let long = "ª".repeat(20);
+        assert_eq!(IdString::from(long.as_str()).as_str(), "ª".repeat(16));
+    }
+}
@@ -1,20 +1,32 @@
 use crate::error::IndraError;
+use fans::FanStatus;
+use identity::PreIdentity;
+use modules::ModuleCount;
+use sdo::{SdoFrame, SdoValue};
+use serde::Serialize;
+use status::PreStatus;
 use std::sync::Arc;
+use supervisor::CommsStatus;
 use tokio::sync::Mutex;
-use tokio_socketcan::CANFrame;
 pub(crate) mod can;
+pub(crate) mod derate;
+pub(crate) mod energy;
 pub(crate) mod fans;
+pub(crate) mod identity;
+pub(crate) mod modules;
+pub(crate) mod poll;
 pub(crate) mod pre_thread;
 pub(crate) mod pwm;
+pub(crate) mod sdo;
+pub(crate) mod slew;
+pub(crate) mod status;
+pub(crate) mod supervisor;
 
 lazy_static::lazy_static! {
     pub static ref PREDATA: Arc<Mutex<PreCharger>> = Arc::new(Mutex::new(PreCharger::default()));
 }
 
-pub const BB_PWM_CHIP: u32 = 0;
-pub const BB_PWM_NUMBER: u32 = 0;
-
-#[derive(Default, Clone, Copy, Debug)]
+#[derive(Serialize, Default, Clone, Copy, Debug)]
 pub enum PreState {
     #[default]
     Offline,
@@ -50,7 +62,11 @@ pub struct PreCharger {
     dc_bus_volts: f32,
     enabled: bool,
     fan_duty: u8,
-    status: [u8; 2],
+    fan: FanStatus,
+    status: PreStatus,
+    identity: PreIdentity,
+    comms: CommsStatus,
+    modules: ModuleCount,
 }
 
 impl std::fmt::Display for PreCharger {
@@ -62,15 +78,16 @@ impl std::fmt::Display for PreCharger {
         };
         write!(
             f,
-            "PRE: {sign} {:.2}W, temp: {:.2}ªC dc_output: {:.2}V {:.2}A, dc_output_setpoint: {:.2}V {:.2}A, fan: {} enabled: {}",
-            self.dc_output_amps * self.dc_output_volts,
+            "PRE: {sign} {:.2}W, temp: {:.2}ªC dc_output: {:.2}V {:.2}A, dc_output_setpoint: {:.2}V {:.2}A, fan: {} enabled: {} status: {}",
+            self.dc_power(),
             self.temp,
             self.dc_output_volts,
             self.dc_output_amps,
             self.dc_output_volts_setpoint,
             self.dc_output_amps_setpoint,
             self.fan_duty,
-            self.enabled
+            self.enabled,
+            self.status
         )
     }
 }
@@ -83,66 +100,65 @@ impl PreCharger {
     pub fn get_state(&self) -> &PreState {
         &self.state
     }
-    pub fn get_status(&self) -> &[u8; 2] {
+    pub fn get_status(&self) -> &PreStatus {
         &self.status
     }
+    pub fn get_identity(&self) -> &PreIdentity {
+        &self.identity
+    }
+    pub fn set_identity(&mut self, identity: PreIdentity) {
+        self.identity = identity
+    }
+    pub fn get_comms(&self) -> &CommsStatus {
+        &self.comms
+    }
+    pub fn set_comms(&mut self, comms: CommsStatus) {
+        self.comms = comms
+    }
+    pub fn get_modules(&self) -> &ModuleCount {
+        &self.modules
+    }
     pub fn get_temp(&self) -> f32 {
         self.temp
     }
     pub fn fan_duty(&mut self, duty: u8) {
         self.fan_duty = duty
     }
+    pub fn get_fan(&self) -> &FanStatus {
+        &self.fan
+    }
+    pub fn set_fan(&mut self, fan: FanStatus) {
+        self.fan = fan
+    }
     pub fn ac_power(&self) -> f32 {
         self.ac_amps * self.ac_volts
     }
+    pub fn dc_power(&self) -> f32 {
+        self.dc_output_amps * self.dc_output_volts
+    }
+    /// Instantaneous conversion efficiency, None at low power
+    pub fn efficiency(&self) -> Option<f32> {
+        energy::efficiency(self.ac_power(), self.dc_power())
+    }
 
-    pub fn from_slice(&mut self, s: &[u8]) -> Result<(), IndraError> {
-        if s.len() != 8 {
-            return Err(IndraError::BadSlice);
-        }
-        match s[0] {
-            0x43 => {
-                // Ack
-                if let Ok(_this) = std::str::from_utf8(&s[4..8]) {
-                    // info!("Decoded ASCII: {_this}");
-                }
-                return Ok(());
-            }
-            0x60 => {
-                // Ack
-                // info!("WRITEACK");
-                return Ok(());
-            }
-            0x80 => {
-                // Error resp
-                log::error!("Can bad ack");
-                return Err(IndraError::Error);
-            }
-            0x4b => (), // passthrough
-            other => {
-                log::error!("Bad request decode {other}");
-                return Err(IndraError::Error); // change to error
-            }
-        }
-        let val_u16 = u16::from_le_bytes([s[4], s[5]]);
-        let val_i16 = i16::from_le_bytes([s[4], s[5]]);
-        let addr = u16::from_le_bytes([s[1], s[2]]);
-
-        match addr {
-            0x2104 => self.temp = val_i16 as f32 * 0.1,
-            0x2105 => self.ac_volts = val_u16 as f32 * 0.1,
-            0x2106 => self.ac_amps = val_i16 as f32 * 0.1,
-            0x2107 => self.dc_output_volts = val_u16 as f32 * 0.1,
-            0x2108 => self.dc_output_amps = val_i16 as f32 * 0.1,
-            0x2109 => self.dc_output_volts_setpoint = val_u16 as f32 * 0.1,
-            0x210a => self.dc_output_amps_setpoint = val_i16 as f32 * 0.1,
-            0x210d => self.dc_bus_volts = val_u16 as f32 * 0.1,
-            0x2100 => self.enabled = 1 == s[4],
-            0x2101 => {
-                self.status = [s[4], s[5]];
-            }
+    /// Stores a polled register value
+    pub fn update(&mut self, register: Register, data: [u8; 4]) -> Result<(), IndraError> {
+        let val_u16 = u16::from_data(data);
+        let val_i16 = i16::from_data(data);
+        match register {
+            Register::Temp => self.temp = val_i16 as f32 * 0.1,
+            Register::AcV => self.ac_volts = val_u16 as f32 * 0.1,
+            Register::AcA => self.ac_amps = val_i16 as f32 * 0.1,
+            Register::DcOutputV => self.dc_output_volts = val_u16 as f32 * 0.1,
+            Register::DcOutputA => self.dc_output_amps = val_i16 as f32 * 0.1,
+            Register::DcBusMaxVsetpoint => self.dc_output_volts_setpoint = val_u16 as f32 * 0.1,
+            Register::DcBusMaxAsetpoint => self.dc_output_amps_setpoint = val_i16 as f32 * 0.1,
+            Register::DcBusV => self.dc_bus_volts = val_u16 as f32 * 0.1,
+            Register::Enabled => self.enabled = 1 == data[0],
+            Register::Status => self.status = PreStatus::from([data[0], data[1]]),
+            Register::Ping => (),
             u => {
-                log::error!("Pre decode unknown address: {u:x}");
+                log::error!("Pre decode unknown register: {u:x?}");
                 return Err(IndraError::Error);
             }
         };
@@ -167,10 +183,12 @@ impl PreCharger {
         self.enabled
     }
     pub fn status_ok(&self) -> bool {
-        [0, 0] == self.status
+        self.status.is_ok()
     }
-    pub fn volts_equal(&self) -> bool {
-        let range = self.get_dc_setpoint_volts() - 2.0..=self.get_dc_setpoint_volts() + 2.0;
+    /// Output within tolerance volts of setpoint
+    pub fn volts_equal(&self, tolerance: f32) -> bool {
+        let range =
+            self.get_dc_setpoint_volts() - tolerance..=self.get_dc_setpoint_volts() + tolerance;
         range.contains(&self.get_dc_output_volts())
     }
 }
@@ -188,9 +206,24 @@ pub enum Register {
     Enabled,
     Status,
     Ping,
+    DeviceName,
+    HardwareVersion,
+    SoftwareVersion,
     Unknown(u16),
 }
 
+impl Register {
+    pub fn index(&self) -> u16 {
+        u16::from(*self)
+    }
+    pub fn subindex(&self) -> u8 {
+        match self {
+            Register::DeviceName | Register::HardwareVersion | Register::SoftwareVersion => 4,
+            _ => 0,
+        }
+    }
+}
+
 impl From<Register> for u16 {
     fn from(reg: Register) -> u16 {
         match reg {
@@ -205,6 +238,9 @@ impl From<Register> for u16 {
             Register::Enabled => 0x2100,
             Register::Status => 0x2101,
             Register::Ping => 0x2150,
+            Register::DeviceName => 0x1008,
+            Register::HardwareVersion => 0x1009,
+            Register::SoftwareVersion => 0x100a,
             Register::Unknown(v) => v,
         }
     }
@@ -223,6 +259,9 @@ impl From<u16> for Register {
             0x2100 => Register::Enabled,
             0x2101 => Register::Status,
             0x2150 => Register::Ping,
+            0x1008 => Register::DeviceName,
+            0x1009 => Register::HardwareVersion,
+            0x100a => Register::SoftwareVersion,
             unknown => Register::Unknown(unknown),
         }
     }
@@ -237,93 +276,28 @@ pub enum PreCommand {
     Shutdown,
 }
 impl PreCommand {
-    pub fn to_can(&self) -> CANFrame {
-        let id = 0x630;
-        let mut data = [0x2b, 0, 0x21, 0, 0, 0, 0, 0];
-
+    /// Command for each of n modules, amps are split equally
+    pub fn share(self, modules: usize) -> Self {
+        match self {
+            PreCommand::DcAmpsSetpoint(a) if modules > 1 => {
+                PreCommand::DcAmpsSetpoint(a / modules as f32)
+            }
+            other => other,
+        }
+    }
+    /// SDO write request for the command
+    pub fn request(&self) -> SdoFrame {
         match self {
             PreCommand::DcVoltsSetpoint(v) => {
-                data[1] = 9;
-                [data[4], data[5]] = u16::to_le_bytes((*v * 10.0) as u16)
+                SdoFrame::download(Register::DcBusMaxVsetpoint, (*v * 10.0) as u16)
             }
             PreCommand::DcAmpsSetpoint(a) => {
-                if a.is_sign_negative() {
-                    (data[6], data[7]) = (0xff, 0xff)
-                }
-                data[1] = 0xa;
-                [data[4], data[5]] = i16::to_le_bytes((*a * 10.0) as i16)
+                SdoFrame::download(Register::DcBusMaxAsetpoint, (*a * 10.0) as i16)
+            }
+            PreCommand::Enable => SdoFrame::download(Register::Enabled, 1u16),
+            PreCommand::Disable | PreCommand::Shutdown => {
+                SdoFrame::download(Register::Enabled, 0u16)
             }
-            PreCommand::Enable => data[4] = 0x1,
-            _ => (),
-        };
-        CANFrame::new(id, &data, false, false).unwrap()
-    }
-}
-
-#[derive(Debug)]
-enum Command {
-    Read,
-    Write2b,
-    String,
-    TwoBytes,
-    WriteAck,
-    Error,
-    Unknown,
-}
-impl Into<u8> for Command {
-    fn into(self) -> u8 {
-        use Command::*;
-        match self {
-            Read => 0x40,
-            Write2b => 0x2b,
-            String => 0x43,
-            TwoBytes => 0x4b,
-            WriteAck => 0x60,
-            Error => 0x80,
-            Unknown => panic!("Bad data enum decode 1"),
-        }
-    }
-}
-
-impl From<u8> for Command {
-    fn from(value: u8) -> Self {
-        use Command::*;
-        match value {
-            0x40 => Read,
-            0x2b => Write2b,
-            0x43 => String,
-            0x4b => TwoBytes,
-            0x60 => WriteAck,
-            0x80 => Error,
-            _ => Unknown,
         }
     }
 }
-pub fn cmd_list_setpoints() -> [u16; 5] {
-    [
-        // u16::from(Register::Temp),
-        // u16::from(Register::AcA),
-        // u16::from(Register::DcOutputV),
-        u16::from(Register::DcBusV),
-        // u16::from(Register::DcOutputA),
-        u16::from(Register::DcBusMaxVsetpoint),
-        u16::from(Register::DcBusMaxAsetpoint),
-        // u16::from(Register::Enabled),
-        u16::from(Register::Status),
-        u16::from(Register::Ping),
-    ]
-}
-pub fn cmd_list_outputs() -> [u16; 5] {
-    [
-        u16::from(Register::Temp),
-        u16::from(Register::AcA),
-        u16::from(Register::DcOutputV),
-        // u16::from(Register::DcBusV),
-        u16::from(Register::DcOutputA),
-        // u16::from(Register::DcBusMaxVsetpoint),
-        // u16::from(Register::DcBusMaxAsetpoint),
-        u16::from(Register::Enabled),
-        // u16::from(Register::Status),
-        // u16::from(Register::Ping),
-    ]
-}
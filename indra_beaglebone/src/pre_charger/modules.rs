@@ -0,0 +1,182 @@
+use super::{
+    identity::PreIdentity,
+    status::PreStatus,
+    supervisor::{CommsState, CommsStatus},
+    PreCharger, PreState,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+lazy_static::lazy_static! {
+    pub static ref PRE_MODULES: Arc<Mutex<Vec<ModuleStatus>>> = Arc::new(Mutex::new(Vec::new()));
+}
+
+/// Modules still sharing current out of those configured
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct ModuleCount {
+    pub active: u8,
+    pub total: u8,
+}
+
+impl Default for ModuleCount {
+    fn default() -> Self {
+        Self {
+            active: 1,
+            total: 1,
+        }
+    }
+}
+
+impl ModuleCount {
+    /// Combined output current of the modules still sharing
+    pub fn max_amps(&self, module_amps: f32) -> f32 {
+        module_amps * self.active as f32
+    }
+}
+
+/// Per module telemetry
+#[derive(Serialize, Debug, Clone)]
+pub struct ModuleStatus {
+    pub node: u8,
+    pub state: PreState,
+    /// Why the module was dropped from sharing
+    pub isolated: Option<&'static str>,
+    pub temp: f32,
+    pub dc_output_volts: f32,
+    pub dc_output_amps: f32,
+    pub ac_kw: f32,
+    pub status: PreStatus,
+    pub comms: CommsStatus,
+    pub identity: PreIdentity,
+}
+
+impl ModuleStatus {
+    pub fn new(node: u8, pre: &PreCharger, isolated: Option<&'static str>) -> Self {
+        Self {
+            node,
+            state: pre.state,
+            isolated,
+            temp: pre.temp,
+            dc_output_volts: pre.dc_output_volts,
+            dc_output_amps: pre.dc_output_amps,
+            ac_kw: pre.ac_power() * 0.001,
+            status: pre.status,
+            comms: pre.comms,
+            identity: pre.identity,
+        }
+    }
+}
+
+fn severity(state: CommsState) -> u8 {
+    match state {
+        CommsState::Ok => 0,
+        CommsState::Degraded => 1,
+        CommsState::Lost => 2,
+        CommsState::Recovering => 3,
+    }
+}
+
+/// Combined view of parallel modules, currents & power add, volts average, worst case wins
+pub fn aggregate(modules: &[PreCharger], count: ModuleCount) -> PreCharger {
+    let Some(first) = modules.first() else {
+        return PreCharger {
+            modules: count,
+            ..Default::default()
+        };
+    };
+    let n = modules.len() as f32;
+    let sum = |f: fn(&PreCharger) -> f32| modules.iter().map(f).sum::<f32>();
+    let state = match modules.iter().any(|m| m.state.is_online()) {
+        true => PreState::Online,
+        false => first.state,
+    };
+    let bits = modules.iter().fold(0, |bits, m| bits | m.status.bits());
+    let comms = CommsStatus {
+        state: modules
+            .iter()
+            .map(|m| m.comms.state)
+            .max_by_key(|s| severity(*s))
+            .unwrap_or_default(),
+        restarts: modules.iter().map(|m| m.comms.restarts).max().unwrap_or(0),
+        stale_ms: modules.iter().map(|m| m.comms.stale_ms).max().unwrap_or(0),
+    };
+    PreCharger {
+        state,
+        temp: modules.iter().map(|m| m.temp).fold(f32::MIN, f32::max),
+        ac_volts: sum(|m| m.ac_volts) / n,
+        ac_amps: sum(|m| m.ac_amps),
+        dc_output_volts: sum(|m| m.dc_output_volts) / n,
+        dc_output_amps: sum(|m| m.dc_output_amps),
+        dc_output_volts_setpoint: sum(|m| m.dc_output_volts_setpoint) / n,
+        dc_output_amps_setpoint: sum(|m| m.dc_output_amps_setpoint),
+        dc_bus_volts: sum(|m| m.dc_bus_volts) / n,
+        enabled: modules.iter().any(|m| m.enabled),
+        status: PreStatus::from(bits.to_le_bytes()),
+        identity: first.identity,
+        comms,
+        modules: count,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pre_charger::PreCommand;
+    #[test]
+    fn aggregate_test() {
+        let module = |amps: f32, temp: f32, status: u16| PreCharger {
+            state: PreState::Online,
+            temp,
+            dc_output_volts: 370.0,
+            dc_output_amps: amps,
+            dc_output_amps_setpoint: amps,
+            enabled: true,
+            status: PreStatus::from(status.to_le_bytes()),
+            ..Default::default()
+        };
+        let count = ModuleCount {
+            active: 2,
+            total: 2,
+        };
+        let pre = aggregate(
+            &[
+                module(8.0, 40.0, 0),
+                module(7.5, 52.0, PreStatus::FAN_FAULT),
+            ],
+            count,
+        );
+        assert_eq!(pre.get_dc_output_amps(), 15.5);
+        assert_eq!(pre.get_dc_setpoint_amps(), 15.5);
+        assert_eq!(pre.get_dc_output_volts(), 370.0);
+        assert_eq!(pre.get_temp(), 52.0);
+        assert!(pre.get_status().contains(PreStatus::FAN_FAULT));
+        assert!(pre.get_state().is_online());
+        assert_eq!(pre.get_modules().max_amps(16.0), 32.0);
+
+        let mut lost = module(0.0, 30.0, 0);
+        lost.comms.state = CommsState::Lost;
+        let pre = aggregate(&[module(8.0, 40.0, 0), lost], count);
+        assert_eq!(pre.get_comms().state, CommsState::Lost);
+
+        let pre = aggregate(&[], ModuleCount::default());
+        assert!(pre.get_state().is_offline());
+    }
+    #[test]
+    fn share_test() {
+        let count = ModuleCount {
+            active: 1,
+            total: 2,
+        };
+        assert_eq!(count.max_amps(16.0), 16.0);
+        assert!(matches!(
+            PreCommand::DcAmpsSetpoint(15.0).share(2),
+            PreCommand::DcAmpsSetpoint(a) if a == 7.5
+        ));
+        assert!(matches!(
+            PreCommand::DcVoltsSetpoint(370.0).share(2),
+            PreCommand::DcVoltsSetpoint(v) if v == 370.0
+        ));
+    }
+}
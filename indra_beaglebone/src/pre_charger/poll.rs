@@ -0,0 +1,239 @@
+use super::{
+    sdo::{Command, SdoFrame},
+    Register,
+};
+use crate::data_io::config::PrePollConfig;
+use std::{collections::VecDeque, time::Duration};
+use tokio::time::Instant;
+
+/// Resends of an unanswered setpoint write before it is dropped
+const WRITE_RETRIES: u8 = 2;
+
+const POLLED: [Register; 11] = [
+    Register::DcOutputV,
+    Register::DcOutputA,
+    Register::DcBusV,
+    Register::Status,
+    Register::Enabled,
+    Register::DcBusMaxVsetpoint,
+    Register::DcBusMaxAsetpoint,
+    Register::Temp,
+    Register::AcV,
+    Register::AcA,
+    Register::Ping,
+];
+
+#[derive(Debug)]
+struct PollEntry {
+    register: Register,
+    period: Duration,
+    next: Instant,
+    sent: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct WriteInFlight {
+    frame: SdoFrame,
+    sent: Instant,
+    attempts: u8,
+}
+
+/// Per register poll schedule, requests are pipelined and matched to responses by index
+#[derive(Debug)]
+pub struct PollPlan {
+    entries: Vec<PollEntry>,
+    writes: VecDeque<SdoFrame>,
+    write: Option<WriteInFlight>,
+    timeout: Duration,
+    max_in_flight: usize,
+}
+
+impl PollPlan {
+    pub fn new(config: &PrePollConfig, now: Instant) -> Self {
+        let entries = POLLED
+            .into_iter()
+            .filter_map(|register| {
+                let period = config.period(register)?;
+                Some(PollEntry {
+                    register,
+                    period,
+                    next: now,
+                    sent: None,
+                })
+            })
+            .collect();
+        Self {
+            entries,
+            writes: VecDeque::new(),
+            write: None,
+            timeout: Duration::from_millis(config.timeout_ms),
+            max_in_flight: config.max_in_flight.max(1),
+        }
+    }
+
+    /// Queues a setpoint write, replacing any queued write to the same register
+    pub fn write(&mut self, frame: SdoFrame) {
+        match self.writes.iter_mut().find(|w| w.answers(&frame)) {
+            Some(queued) => *queued = frame,
+            None => self.writes.push_back(frame),
+        }
+    }
+
+    /// Write to send now, one in flight at a time and resent until answered
+    pub fn next_write(&mut self, now: Instant) -> Option<SdoFrame> {
+        if let Some(write) = self.write.as_mut() {
+            if now.duration_since(write.sent) < self.timeout {
+                return None;
+            }
+            if write.attempts <= WRITE_RETRIES {
+                write.attempts += 1;
+                write.sent = now;
+                return Some(write.frame);
+            }
+            log::error!("Pre write unanswered {}", write.frame);
+        }
+        self.write = self.writes.pop_front().map(|frame| WriteInFlight {
+            frame,
+            sent: now,
+            attempts: 1,
+        });
+        self.write.as_ref().map(|w| w.frame)
+    }
+
+    /// Reads due and not already awaiting an answer, most overdue first
+    pub fn due(&mut self, now: Instant) -> Vec<Register> {
+        let room = self.max_in_flight.saturating_sub(self.in_flight());
+        let mut due: Vec<&mut PollEntry> = self
+            .entries
+            .iter_mut()
+            .filter(|e| e.sent.is_none() && e.next <= now)
+            .collect();
+        due.sort_by_key(|e| e.next);
+        due.into_iter()
+            .take(room)
+            .map(|entry| {
+                entry.sent = Some(now);
+                // skip missed slots rather than bursting to catch up
+                entry.next = (entry.next + entry.period).max(now);
+                entry.register
+            })
+            .collect()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.entries.iter().filter(|e| e.sent.is_some()).count() + self.write.iter().count()
+    }
+
+    /// Matches a response to the request in flight, false if nothing was waiting on it
+    pub fn answered(&mut self, response: &SdoFrame) -> bool {
+        let write = matches!(response.command, Command::DownloadResponse | Command::Abort);
+        if write
+            && self
+                .write
+                .as_ref()
+                .is_some_and(|w| response.answers(&w.frame))
+        {
+            self.write = None;
+            return true;
+        }
+        if matches!(response.command, Command::DownloadResponse) {
+            return false;
+        }
+        match self
+            .entries
+            .iter_mut()
+            .find(|e| e.sent.is_some() && e.register.index() == response.index)
+        {
+            Some(entry) => {
+                entry.sent = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Reads unanswered within the timeout, sent again when next due
+    pub fn expired(&mut self, now: Instant) -> Vec<Register> {
+        let timeout = self.timeout;
+        self.entries
+            .iter_mut()
+            .filter(|e| {
+                e.sent
+                    .is_some_and(|sent| now.duration_since(sent) >= timeout)
+            })
+            .map(|entry| {
+                entry.sent = None;
+                entry.register
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pre_charger::sdo::SdoValue;
+    fn config() -> PrePollConfig {
+        PrePollConfig {
+            dc_output_ms: 100,
+            dc_bus_ms: 0,
+            status_ms: 0,
+            setpoint_ms: 0,
+            temp_ms: 1000,
+            ac_ms: 0,
+            ping_ms: 0,
+            timeout_ms: 50,
+            max_in_flight: 2,
+        }
+    }
+    #[test]
+    fn due_test() {
+        let start = Instant::now();
+        let mut plan = PollPlan::new(&config(), start);
+        // limited by max in flight
+        assert_eq!(plan.due(start).len(), 2);
+        assert!(plan.due(start).is_empty());
+        let response = SdoFrame {
+            command: Command::UploadResponse(2),
+            index: Register::DcOutputV.index(),
+            subindex: 0,
+            data: [0; 4],
+        };
+        assert!(plan.answered(&response));
+        assert!(!plan.answered(&response));
+        // temp
+        assert_eq!(plan.due(start).len(), 1);
+        // DcOutputA unanswered
+        let expired = plan.expired(start + Duration::from_millis(50));
+        assert_eq!(expired.len(), 2);
+        let later = start + Duration::from_millis(100);
+        assert_eq!(plan.due(later).len(), 2); // dc volts & amps again, temp not due
+        assert!(plan.due(start + Duration::from_millis(1000)).is_empty());
+    }
+    #[test]
+    fn write_test() {
+        let start = Instant::now();
+        let mut plan = PollPlan::new(&config(), start);
+        plan.write(SdoFrame::download(Register::DcBusMaxAsetpoint, 10i16));
+        plan.write(SdoFrame::download(Register::DcBusMaxAsetpoint, 20i16));
+        plan.write(SdoFrame::download(Register::Enabled, 1u16));
+        // superseded setpoint replaced in the queue
+        let first = plan.next_write(start).unwrap();
+        assert_eq!(i16::from_data(first.data), 20);
+        assert!(plan.next_write(start).is_none());
+        // resent after timeout
+        let retry = start + Duration::from_millis(50);
+        assert_eq!(plan.next_write(retry).unwrap().index, first.index);
+        let ack = SdoFrame {
+            command: Command::DownloadResponse,
+            index: first.index,
+            subindex: 0,
+            data: [0; 4],
+        };
+        assert!(plan.answered(&ack));
+        assert_eq!(
+            plan.next_write(retry).unwrap().register().index(),
+            Register::Enabled.index()
+        );
+    }
+}
@@ -1,127 +1,424 @@
 use super::{
-    can::*, fans::*, pwm::Pwm, PreCharger, PreCommand, BB_PWM_CHIP, BB_PWM_NUMBER, PREDATA,
+    can::*,
+    energy::{EnergySession, ENERGY},
+    fans::*,
+    modules::{self, ModuleCount, ModuleStatus, PRE_MODULES},
+    poll::PollPlan,
+    sdo::{AbortCode, Command, SdoClient, SdoFrame, SdoValue},
+    status::FaultAction,
+    supervisor::{CommsState, Supervisor},
+    PreCharger, PreCommand, PREDATA,
 };
 use crate::{
     chademo::state::{pin_init_out_high, PREACPIN},
-    data_io::mqtt::CHADEMO_DATA,
+    data_io::{
+        config::{PreSupervisorConfig, APP_CONFIG},
+        mqtt::CHADEMO_DATA,
+        wear::{self, Device},
+    },
     error::IndraError,
     global_state::OperationMode,
     log_error,
-    pre_charger::{cmd_list_outputs, cmd_list_setpoints, PreState},
+    pre_charger::PreState,
+    safety::startup,
     statics::PreRxMutex,
+    POOL,
 };
+use chrono::{Local, Utc};
 use std::time::Duration;
 use sysfs_gpio::Pin;
-use tokio::time::{sleep, timeout, Instant};
-use tokio_socketcan::CANFrame;
+use tokio::time::{sleep, Instant};
+
+/// Responses are collected for a tick before the next requests go out
+const TICK: Duration = Duration::from_millis(10);
+
+/// One Pre power module on can0
+struct Module {
+    sdo: SdoClient,
+    plan: PollPlan,
+    supervisor: Supervisor,
+    pre: PreCharger,
+    /// Why the module was dropped from sharing
+    isolated: Option<&'static str>,
+}
+
+impl Module {
+    fn open(node: u8, max_misses: u8) -> Result<Self, IndraError> {
+        Ok(Self {
+            sdo: SdoClient::open("can0", node)?,
+            plan: PollPlan::new(&APP_CONFIG.pre_poll, Instant::now()),
+            supervisor: Supervisor::new(max_misses),
+            pre: PreCharger::default(),
+            isolated: None,
+        })
+    }
+    fn is_active(&self) -> bool {
+        self.isolated.is_none()
+    }
+    /// Disables the module & stops polling it until the next power cycle
+    async fn isolate(&mut self, reason: &'static str) {
+        log::error!("Pre node {:#x} isolated - {reason}", self.sdo.node());
+        log_error!(
+            "Disable isolated Pre",
+            self.sdo.submit(&PreCommand::Disable.request()).await
+        );
+        self.isolated = Some(reason);
+    }
+    /// Fault that drops the module while others remain
+    fn fault(&self) -> Option<&'static str> {
+        if self.supervisor.state() == CommsState::Lost {
+            return Some("comms lost");
+        }
+        match self.pre.get_status().action() {
+            Some(FaultAction::Stop) => Some("Pre fault"),
+            _ => None,
+        }
+    }
+}
+
+fn active_count(modules: &[Module]) -> usize {
+    modules.iter().filter(|m| m.is_active()).count()
+}
+
+/// Combined output current, recorded as the load when the AC contactor opens
+fn dc_amps(modules: &[Module]) -> f32 {
+    modules.iter().map(|m| m.pre.get_dc_output_amps()).sum()
+}
 
 pub async fn init(pre_rx_m: PreRxMutex) -> Result<(), IndraError> {
     log::info!("Starting Pre thread {}", tokio::task::id());
     let t100ms = Duration::from_millis(100);
+    let config = &APP_CONFIG.pre_supervisor;
+    let mut modules = APP_CONFIG
+        .pre_modules
+        .nodes
+        .iter()
+        .map(|node| Module::open(*node, config.max_misses))
+        .collect::<Result<Vec<_>, _>>()?;
+    if modules.is_empty() {
+        log::error!("No Pre modules configured");
+        return Err(IndraError::PreInitFailed);
+    }
     let mut pre = PreCharger::default();
-    let mut can_socket =
-        tokio_socketcan::CANSocket::open("can0").map_err(|e| IndraError::CanOpen(e))?;
     let predata = PREDATA.clone();
     predata.lock().await.set_state(PreState::Init);
-    let pwm = Pwm::new(BB_PWM_CHIP, BB_PWM_NUMBER, 1000).unwrap(); // number depends on chip, etc.
-    let mut fan = Fan::new(pwm);
-    fan.update(10.0); // turn fans off
+    let fan = FAN.clone();
+    fan.lock().await.off();
     let pre_ac_contactor: Pin = pin_init_out_high(PREACPIN)?;
+    wear::closed(Device::PreAc);
+    record_session(
+        ENERGY
+            .lock()
+            .ok()
+            .and_then(|mut e| e.start_session(Utc::now())),
+    );
     sleep(t100ms * 10).await;
 
-    initalise_pre(t100ms, &mut can_socket, &mut pre).await?;
-
-    let setpoints = cmd_list_setpoints();
-    let outputs = cmd_list_outputs();
+    if !initalise_modules(t100ms, &mut modules).await {
+        recover(t100ms, &mut modules, &pre_ac_contactor, config).await?;
+    }
     let mut pre_rx = pre_rx_m.lock().await;
+    let mut housekeeping = Instant::now();
     let mut counter = 0;
+    let mut shutdown = false;
+    // total amps setpoint, reshared when a module drops
+    let mut amps = None;
     loop {
-        counter += 1;
-        let instant = Instant::now();
-        let cmd_list = if counter % 2 == 0 { setpoints } else { outputs };
-        read_pre(&cmd_list, &mut can_socket, t100ms, &mut pre).await;
-        update_fan(&mut pre, &mut fan);
-
-        if let Ok(cmd) = pre_rx.try_recv() {
+        let now = Instant::now();
+        // setpoint writes go out ahead of polling
+        while let Ok(cmd) = pre_rx.try_recv() {
             log::debug!("Received {cmd:?}");
-            if !matches!(cmd, PreCommand::Shutdown) {
-                write_pre(cmd, &mut can_socket, t100ms / 10, &mut pre).await;
-            } else {
-                fan.stop();
-                pre.set_state(PreState::Offline);
-                pre.fan_duty(1);
+            if let PreCommand::Shutdown = cmd {
+                fan.lock().await.stop();
+                shutdown = true;
+                continue;
+            }
+            if let PreCommand::DcAmpsSetpoint(a) = cmd {
+                amps = Some(a);
+            }
+            let active = active_count(&modules);
+            for module in modules.iter_mut().filter(|m| m.is_active()) {
+                module.plan.write(cmd.share(active).request());
+            }
+        }
+        for module in modules.iter_mut().filter(|m| m.is_active()) {
+            if let Some(request) = module.plan.next_write(now) {
+                log::debug!("New pre_cmd {request}");
+                log_error!("Send pre cmd", module.sdo.submit(&request).await);
+            }
+            for register in module.plan.due(now) {
+                log_error!(
+                    format!("Pre read {register:?}"),
+                    module.sdo.submit(&SdoFrame::upload(register)).await
+                );
+            }
+        }
+        for module in modules.iter_mut().filter(|m| m.is_active()) {
+            while let Ok(response) = module.sdo.next_response(now + TICK).await {
+                read_response(response, module);
             }
-            // let mut data = predata.lock().await;
+            for register in module.plan.expired(Instant::now()) {
+                log::debug!("Pre read {register:?} unanswered");
+                module.supervisor.record(register, false, Instant::now());
+            }
+            module
+                .pre
+                .set_comms(module.supervisor.status(Instant::now()));
+        }
+
+        // a faulted module is dropped while the others can carry on
+        let mut dropped = false;
+        let mut active = active_count(&modules);
+        for module in modules.iter_mut().filter(|m| m.is_active()) {
+            if active < 2 {
+                break;
+            }
+            if let Some(reason) = module.fault() {
+                module.isolate(reason).await;
+                active -= 1;
+                dropped = true;
+            }
+        }
+        if let (true, Some(amps)) = (dropped, amps) {
+            for module in modules.iter_mut().filter(|m| m.is_active()) {
+                module
+                    .plan
+                    .write(PreCommand::DcAmpsSetpoint(amps).share(active).request());
+            }
+        }
+        if let Some(lost) = modules
+            .iter()
+            .find(|m| m.is_active() && m.supervisor.state() == CommsState::Lost)
+        {
+            log::error!(
+                "Pre comms lost, no answer for {}ms",
+                lost.pre.get_comms().stale_ms
+            );
+            recover(t100ms, &mut modules, &pre_ac_contactor, config).await?;
+            continue;
+        }
+        let dt = housekeeping.elapsed();
+        if dt < t100ms {
+            continue;
+        }
+        housekeeping = Instant::now();
+        pre = telemetry(&modules, &pre);
+        if shutdown {
+            pre.set_state(PreState::Offline);
+            pre.fan_duty(1);
+        }
+        // held by a fan test, never wait on it here
+        if let Ok(mut fan) = fan.try_lock() {
+            update_fan(&mut pre, &mut fan);
         }
 
+        let energy = match ENERGY.lock() {
+            Ok(mut energy) => {
+                if pre.state.is_online() {
+                    energy.add(pre.ac_power(), pre.dc_power(), dt, Local::now());
+                }
+                energy.report()
+            }
+            Err(_) => Default::default(),
+        };
+
         // update MQTT struct
         {
+            *PRE_MODULES.lock().await = statuses(&modules);
             *predata.lock().await = pre;
         }
         if let Ok(mut data) = CHADEMO_DATA.try_write() {
-            data.from_pre(pre);
+            data.from_pre(pre).from_energy(energy);
             if matches!(pre.state, PreState::Offline) {
-                pre_ac_contactor
-                    .set_value(0)
-                    .map_err(|e| IndraError::PinAccess(e))?;
+                wear::switch(
+                    Device::PreAc,
+                    &pre_ac_contactor,
+                    0,
+                    pre.get_dc_output_amps(),
+                )
+                .map_err(|e| IndraError::PinAccess(e))?;
                 log::warn!("Pre AC contactor opened");
+                end_session();
                 return Ok(());
             };
         };
 
         // 1 sec Pre stats
+        counter += 1;
         if counter > 10 {
             println!("{}", pre);
             counter = 0;
         }
-        if instant.elapsed().as_millis().gt(&99) {
-            log::warn!("loop time > 100ms");
-            dbg!(instant.elapsed().as_millis());
-        } else {
-            sleep(t100ms - instant.elapsed()).await
+    }
+}
+
+/// Initialises every module, those that fail are isolated, false if none came up
+async fn initalise_modules(t100ms: Duration, modules: &mut [Module]) -> bool {
+    for module in modules.iter_mut() {
+        if let Err(e) = initalise_pre(t100ms, &mut module.sdo, &mut module.pre).await {
+            log::error!("Pre node {:#x} init {e}", module.sdo.node());
+            module.isolate("init failed").await;
         }
+        module.plan = PollPlan::new(&APP_CONFIG.pre_poll, Instant::now());
+    }
+    let active = active_count(modules);
+    if active > 0 && active < modules.len() {
+        log::warn!("{active} of {} Pre modules online", modules.len());
     }
+    active > 0
 }
 
-async fn write_pre(
-    cmd: PreCommand,
-    can_socket: &mut tokio_socketcan::CANSocket,
+/// Power cycles & reinitialises all modules while restarts remain, otherwise takes them offline
+async fn recover(
     t100ms: Duration,
-    pre: &mut PreCharger,
-) {
-    log::debug!("New pre_cmd {:?}", cmd);
-    let frame = cmd.to_can();
-    if let Ok(rx) = can_send_recv(can_socket, frame, t100ms).await {
-        log_error!("Send pre cmd", pre.from_slice(rx.data()));
+    modules: &mut [Module],
+    pre_ac: &Pin,
+    config: &PreSupervisorConfig,
+) -> Result<(), IndraError> {
+    loop {
+        let phase = startup::phase();
+        let restarts = modules
+            .iter()
+            .map(|m| m.supervisor.restarts())
+            .max()
+            .unwrap_or(0);
+        if !config.power_cycle || restarts >= config.max_restarts || phase.is_energised() {
+            log::error!("Pre offline after {restarts} restarts ({phase:?})");
+            let amps = dc_amps(modules);
+            for module in modules.iter_mut() {
+                let mut comms = module.supervisor.status(Instant::now());
+                comms.state = CommsState::Lost;
+                module.pre.set_comms(comms);
+                module.pre.set_state(PreState::Offline);
+            }
+            publish(modules).await;
+            wear::switch(Device::PreAc, pre_ac, 0, amps).map_err(|e| IndraError::PinAccess(e))?;
+            log::warn!("Pre AC contactor opened");
+            end_session();
+            return Err(IndraError::PreCommsLost);
+        }
+
+        log::warn!("Pre power cycle {}/{}", restarts + 1, config.max_restarts);
+        let amps = dc_amps(modules);
+        for module in modules.iter_mut() {
+            module.supervisor.restart();
+            module.pre = PreCharger::default();
+            module.isolated = None;
+            let mut comms = module.supervisor.status(Instant::now());
+            comms.state = CommsState::Recovering;
+            module.pre.set_comms(comms);
+            module.pre.set_state(PreState::Init);
+        }
+        publish(modules).await;
+        wear::switch(Device::PreAc, pre_ac, 0, amps).map_err(|e| IndraError::PinAccess(e))?;
+        sleep(Duration::from_secs(config.off_secs)).await;
+        wear::switch(Device::PreAc, pre_ac, 1, 0.0).map_err(|e| IndraError::PinAccess(e))?;
+        sleep(t100ms * 10).await;
+
+        if initalise_modules(t100ms, modules).await {
+            for module in modules.iter_mut() {
+                module
+                    .pre
+                    .set_comms(module.supervisor.status(Instant::now()));
+            }
+            log::info!("Pre recovered");
+            return Ok(());
+        }
+    }
+}
+
+/// Aggregate of the active modules, all of them if none are left
+fn telemetry(modules: &[Module], last: &PreCharger) -> PreCharger {
+    let count = ModuleCount {
+        active: active_count(modules) as u8,
+        total: modules.len() as u8,
+    };
+    let active: Vec<PreCharger> = modules
+        .iter()
+        .filter(|m| m.is_active() || count.active == 0)
+        .map(|m| m.pre)
+        .collect();
+    let mut pre = modules::aggregate(&active, count);
+    pre.fan_duty = last.fan_duty;
+    pre.fan = last.fan;
+    pre
+}
+
+fn statuses(modules: &[Module]) -> Vec<ModuleStatus> {
+    modules
+        .iter()
+        .map(|m| ModuleStatus::new(m.sdo.node(), &m.pre, m.isolated))
+        .collect()
+}
+
+async fn publish(modules: &[Module]) {
+    let pre = telemetry(modules, &*PREDATA.lock().await);
+    *PRE_MODULES.lock().await = statuses(modules);
+    *PREDATA.lock().await = pre;
+    if let Ok(mut data) = CHADEMO_DATA.try_write() {
+        data.from_pre(pre);
+    }
+}
+
+fn end_session() {
+    record_session(
+        ENERGY
+            .lock()
+            .ok()
+            .and_then(|mut e| e.end_session(Utc::now())),
+    );
+}
+
+/// Stores a finished energy session, sessions that moved no energy are dropped
+fn record_session(session: Option<EnergySession>) {
+    let Some(session) = session.filter(|s| !s.totals.is_empty()) else {
+        return;
     };
+    let totals = session.totals;
+    log::info!(
+        "Energy session AC {:.0}/{:.0}Wh DC {:.0}/{:.0}Wh in/out, loss {:.0}Wh",
+        totals.ac_in_wh,
+        totals.ac_out_wh,
+        totals.dc_in_wh,
+        totals.dc_out_wh,
+        totals.loss_wh()
+    );
+    tokio::spawn(async move {
+        let Some(db) = POOL.get() else { return };
+        if let Err(e) = db.add_energy_session(&session).await {
+            log::error!("db energy {e:?}");
+        }
+    });
 }
 
 fn update_fan(pre: &mut PreCharger, fan: &mut Fan) {
-    if pre.enabled() || pre.get_temp() > 55.0 {
+    if pre.enabled() || fan.needed(pre.get_temp()) {
         pre.fan_duty(fan.update(pre.get_temp()));
     } else {
-        fan.update(10.0);
-        pre.fan_duty(fan.update(10.0));
+        pre.fan_duty(fan.off());
     }
+    pre.set_fan(fan.status());
 }
 
-async fn read_pre(
-    cmd_list: &[u16],
-    can_socket: &mut tokio_socketcan::CANSocket,
-    t100ms: Duration,
-    pre: &mut PreCharger,
-) {
-    for address in cmd_list.iter() {
-        let addr = address.to_le_bytes();
-        let p: [u8; 8] = [0x40, addr[0], addr[1], 0, 0, 0, 0, 0];
-        // p[0] = 0x40;
-
-        let frame = CANFrame::new(0x630, &p, false, false).unwrap();
-
-        if let Ok(rx) = can_send_recv(can_socket, frame, t100ms).await {
-            if pre.from_slice(rx.data()).is_ok() {
-                // break;
-            }
+/// Stores an answer to a pipelined request
+fn read_response(response: SdoFrame, module: &mut Module) {
+    if !module.plan.answered(&response) {
+        log::debug!("Rx<<Pre dropped uncorrelated {response}");
+        return;
+    }
+    let register = response.register();
+    module.supervisor.record(register, true, Instant::now());
+    match response.command {
+        Command::UploadResponse(_) => {
+            log_error!(
+                format!("Pre {register:?}"),
+                module.pre.update(register, response.data)
+            )
         }
+        Command::Abort => log::warn!(
+            "Pre {register:?} {}",
+            AbortCode(u32::from_data(response.data))
+        ),
+        _ => (),
     }
 }
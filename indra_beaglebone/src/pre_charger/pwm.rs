@@ -16,14 +16,21 @@
 //!
 //! Modified for Debian Buster /sys
 //! Added duty_cycle() abstraction and fixed frequency in new()
+//! Configurable sysfs root, duty read-back
 
+use crate::data_io::config::PwmConfig;
 pub use error::Error;
 use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
 
 #[derive(Debug)]
 pub struct PwmChip {
     pub pwm_id: u32,
+    root: PathBuf,
 }
 
 #[derive(Debug)]
@@ -42,61 +49,64 @@ pub enum Polarity {
 
 pub type Result<T> = ::std::result::Result<T, error::Error>;
 
+/// Channel attribute path, Debian Buster layout
+fn pwm_path(chip: &PwmChip, channel: u32, name: &str) -> PathBuf {
+    chip.root
+        .join(format!("pwm-{}:{}", chip.pwm_id, channel))
+        .join(name)
+}
+
 /// Open the specified entry name as a writable file
 fn pwm_file_wo(chip: &PwmChip, channel: u32, name: &str) -> Result<File> {
-    log::debug!(
-        "pwm_file_wo => /sys/class/pwm/{}/pwm-{}:{}/",
-        chip.pwm_id,
-        channel,
-        name
-    );
-    let f = (OpenOptions::new().write(true).open(format!(
-        "/sys/class/pwm/pwm-{}:{}/{}",
-        chip.pwm_id, channel, name
-    )))?;
+    let path = pwm_path(chip, channel, name);
+    log::debug!("pwm_file_wo => {}", path.display());
+    let f = OpenOptions::new().write(true).truncate(true).open(path)?;
     Ok(f)
 }
 
 /// Open the specified entry name as a readable file
-// #[allow(dead_code)]
-// fn pwm_file_ro(chip: &PwmChip, channel: u32, name: &str) -> Result<File> {
-//     let f = File::open(format!(
-//         "/sys/class/pwm/{}/pwm-{}:{}/",
-//         chip.pwm_id, channel, name
-//     ))?;
-//     Ok(f)
-// }
+fn pwm_file_ro(chip: &PwmChip, channel: u32, name: &str) -> Result<File> {
+    let f = File::open(pwm_path(chip, channel, name))?;
+    Ok(f)
+}
 
 /// Get the u32 value from the given entry
-// #[allow(dead_code)]
-// fn pwm_file_parse<T: FromStr>(chip: &PwmChip, channel: u32, name: &str) -> Result<T> {
-//     let mut s = String::with_capacity(10);
-//     let mut f = pwm_file_ro(chip, channel, name)?;
-//     f.read_to_string(&mut s)?;
-//     match s.trim().parse::<T>() {
-//         Ok(r) => Ok(r),
-//         Err(_) => Err(Error::Unexpected(format!(
-//             "Unexpected value in file contents: {:?}",
-//             s
-//         ))),
-//     }
-// }
+fn pwm_file_parse<T: FromStr>(chip: &PwmChip, channel: u32, name: &str) -> Result<T> {
+    let mut s = String::with_capacity(10);
+    let mut f = pwm_file_ro(chip, channel, name)?;
+    f.read_to_string(&mut s)?;
+    match s.trim().parse::<T>() {
+        Ok(r) => Ok(r),
+        Err(_) => Err(Error::Unexpected(format!(
+            "Unexpected value in file contents: {:?}",
+            s
+        ))),
+    }
+}
 
 impl PwmChip {
-    pub fn new(number: u32) -> Result<PwmChip> {
-        log::debug!("Fan new PWM => /sys/class/pwm/pwmchip{}", number);
-        fs::metadata(&format!("/sys/class/pwm/pwmchip{}", number))?;
-        Ok(PwmChip { pwm_id: number })
+    pub fn new(root: &Path, number: u32) -> Result<PwmChip> {
+        let path = root.join(format!("pwmchip{}", number));
+        log::debug!("Fan new PWM => {}", path.display());
+        fs::metadata(&path)?;
+        Ok(PwmChip {
+            pwm_id: number,
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.root.join(format!("pwmchip{}", self.pwm_id)).join(name)
     }
 
     #[allow(dead_code)]
     pub fn count(&self) -> Result<u32> {
-        log::debug!("count => /sys/class/pwm/pwmchip{}/npwm", self.pwm_id);
-        let npwm_path = format!("/sys/class/pwm/pwmchip{}/npwm", self.pwm_id);
+        let npwm_path = self.path("npwm");
+        log::debug!("count => {}", npwm_path.display());
         let mut npwm_file = File::open(&npwm_path)?;
         let mut s = String::new();
         npwm_file.read_to_string(&mut s)?;
-        match s.parse::<u32>() {
+        match s.trim().parse::<u32>() {
             Ok(n) => Ok(n),
             Err(_) => Err(Error::Unexpected(format!(
                 "Unexpected npwm contents: {:?}",
@@ -107,32 +117,21 @@ impl PwmChip {
 
     pub fn export(&self, channel: u32) -> Result<()> {
         // only export if not already exported
-        log::debug!(
-            "Export => /sys/class/pwm/pwmchip{}/pwm-{}:{}",
-            self.pwm_id,
-            self.pwm_id,
-            channel
-        );
-        if let Err(_) = fs::metadata(&format!(
-            "/sys/class/pwm/pwmchip{}/pwm-{}:{}",
-            self.pwm_id, self.pwm_id, channel
-        )) {
-            log::debug!("Export 2 => /sys/class/pwm/pwmchip{}/export", self.pwm_id);
-            let path = format!("/sys/class/pwm/pwmchip{}/export", self.pwm_id);
+        let channel_path = self.path(&format!("pwm-{}:{}", self.pwm_id, channel));
+        log::debug!("Export => {}", channel_path.display());
+        if let Err(_) = fs::metadata(&channel_path) {
+            let path = self.path("export");
+            log::debug!("Export 2 => {}", path.display());
             let mut export_file = File::create(&path)?;
-            let _ = export_file.write_all(format!("{}", channel).as_bytes());
+            export_file.write_all(format!("{}", channel).as_bytes())?;
         }
         Ok(())
     }
 
     pub fn unexport(&self, channel: u32) -> Result<()> {
-        if let Ok(_) = fs::metadata(&format!(
-            "/sys/class/pwm/pwmchip{}/pwm-{}:{}",
-            self.pwm_id, self.pwm_id, channel
-        )) {
-            let path = format!("/sys/class/pwm/pwmchip{}/unexport", self.pwm_id);
-            let mut export_file = File::create(&path)?;
-            let _ = export_file.write_all(format!("{}", channel).as_bytes());
+        if let Ok(_) = fs::metadata(self.path(&format!("pwm-{}:{}", self.pwm_id, channel))) {
+            let mut export_file = File::create(self.path("unexport"))?;
+            export_file.write_all(format!("{}", channel).as_bytes())?;
         }
         Ok(())
     }
@@ -142,11 +141,12 @@ impl Pwm {
     /// Create a new Pwm with the provided chip/number
     ///
     /// This function does not export the Pwm pin
-    pub fn new(chip: u32, channel: u32, freqency: u32) -> Result<Pwm> {
-        let chip: PwmChip = PwmChip::new(chip)?;
-        assert!(freqency < 10_000);
-
-        let period = 10u64.pow(10) / freqency as u64;
+    pub fn new(root: impl AsRef<Path>, chip: u32, channel: u32, frequency: u32) -> Result<Pwm> {
+        let chip: PwmChip = PwmChip::new(root.as_ref(), chip)?;
+        if frequency == 0 || frequency as u64 > NS_PER_SEC {
+            return Err(Error::Unexpected(format!("PWM frequency {frequency}Hz")));
+        }
+        let period = NS_PER_SEC / frequency as u64;
         Ok(Pwm {
             chip: chip,
             channel: channel,
@@ -154,6 +154,10 @@ impl Pwm {
         })
     }
 
+    pub fn from_config(config: &PwmConfig) -> Result<Pwm> {
+        Self::new(&config.root, config.chip, config.channel, config.frequency)
+    }
+
     /// Run a closure with the GPIO exported
     #[allow(dead_code)]
     #[inline]
@@ -187,16 +191,33 @@ impl Pwm {
         Ok(())
     }
 
+    /// Sets duty and reads it back
     pub fn set_duty(&self, percentage: u8) -> Result<()> {
-        assert!(percentage.le(&100));
-        let duty_cycle_ns = (self.period as f32 * (percentage as f32 * 0.01)) as u32;
-        self.set_duty_cycle_ns(duty_cycle_ns)
+        if percentage > 100 {
+            return Err(Error::Unexpected(format!("PWM duty {percentage}%")));
+        }
+        let duty_cycle_ns = (self.period as u64 * percentage as u64 / 100) as u32;
+        self.set_duty_cycle_ns(duty_cycle_ns)?;
+        let read_back = self.get_duty_cycle_ns()?;
+        if read_back != duty_cycle_ns {
+            return Err(Error::Unexpected(format!(
+                "duty_cycle read back {read_back}ns, set {duty_cycle_ns}ns"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Duty as a percentage of the configured period
+    #[allow(dead_code)]
+    pub fn get_duty(&self) -> Result<u8> {
+        let ns = self.get_duty_cycle_ns()? as u64;
+        Ok((ns * 100 / self.period.max(1) as u64).min(100) as u8)
     }
 
     /// Get the currently configured duty_cycle in nanoseconds
-    // pub fn get_duty_cycle_ns(&self) -> Result<u32> {
-    //     pwm_file_parse::<u32>(&self.chip, self.channel, "duty_cycle")
-    // }
+    pub fn get_duty_cycle_ns(&self) -> Result<u32> {
+        pwm_file_parse::<u32>(&self.chip, self.channel, "duty_cycle")
+    }
 
     /// The active time of the PWM signal
     ///
@@ -209,9 +230,10 @@ impl Pwm {
     }
 
     /// Get the currently configured period in nanoseconds
-    // pub fn get_period_ns(&self) -> Result<u32> {
-    //     pwm_file_parse::<u32>(&self.chip, self.channel, "period")
-    // }
+    #[allow(dead_code)]
+    pub fn get_period_ns(&self) -> Result<u32> {
+        pwm_file_parse::<u32>(&self.chip, self.channel, "period")
+    }
 
     /// The period of the PWM signal in Nanoseconds
     fn set_period_ns(&self) -> Result<()> {
@@ -259,3 +281,46 @@ mod error {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    /// pwmchip0 with channel 0 attributes, as sysfs leaves them after export
+    fn fake_tree(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("indra_pwm_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("pwmchip0")).unwrap();
+        let channel = root.join("pwm-0:0");
+        fs::create_dir_all(&channel).unwrap();
+        for attr in ["enable", "period", "duty_cycle"] {
+            fs::write(channel.join(attr), "0").unwrap();
+        }
+        root
+    }
+    #[test]
+    fn duty_read_back_test() {
+        let root = fake_tree("duty");
+        let pwm = Pwm::new(&root, 0, 0, 100).unwrap();
+        pwm.export().unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("pwmchip0/export")).unwrap(),
+            "0"
+        );
+        pwm.enable(true).unwrap();
+        assert_eq!(pwm.get_period_ns().unwrap(), 10_000_000);
+        pwm.set_duty(100).unwrap();
+        // shorter value must not leave digits of the last one behind
+        pwm.set_duty(45).unwrap();
+        assert_eq!(pwm.get_duty_cycle_ns().unwrap(), 4_500_000);
+        assert_eq!(pwm.get_duty().unwrap(), 45);
+        assert!(pwm.set_duty(101).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+    #[test]
+    fn missing_chip_test() {
+        let root = fake_tree("missing");
+        assert!(Pwm::new(&root, 1, 0, 100).is_err());
+        assert!(Pwm::new(&root, 0, 0, 0).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,484 @@
+use super::Register;
+use crate::error::IndraError;
+use futures_util::StreamExt;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_socketcan::{CANFrame, CANSocket};
+
+/// Default Pre node ID
+pub const PRE_NODE: u8 = 0x30;
+/// SDO request COB-ID base, node ID added
+const SDO_REQUEST: u32 = 0x600;
+/// SDO response COB-ID base, node ID added
+///
+/// CiA 301 predefined connection set, the Pre answers on its default server
+/// COB-ID as it's never sent a different one through 0x1200
+const SDO_RESPONSE: u32 = 0x580;
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(100);
+const DEFAULT_RETRIES: u8 = 2;
+/// Segmented uploads longer than this are cut short
+const MAX_SEGMENTED: usize = 256;
+
+/// SDO command specifier, expedited transfers only
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Read request
+    Upload,
+    /// Read response, data length 1-4
+    UploadResponse(u8),
+    /// Read response starting a segmented upload, data is the size
+    UploadSegmented,
+    /// Write request, data length 1-4
+    Download(u8),
+    /// Write acknowledge
+    DownloadResponse,
+    Abort,
+}
+
+impl From<Command> for u8 {
+    fn from(cmd: Command) -> u8 {
+        use Command::*;
+        match cmd {
+            Upload => 0x40,
+            UploadResponse(len) => 0x43 | ((4 - len.clamp(1, 4)) << 2),
+            UploadSegmented => 0x41,
+            Download(len) => 0x23 | ((4 - len.clamp(1, 4)) << 2),
+            DownloadResponse => 0x60,
+            Abort => 0x80,
+        }
+    }
+}
+
+impl TryFrom<u8> for Command {
+    type Error = IndraError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        use Command::*;
+        let len = 4 - ((value >> 2) & 0x3);
+        match value {
+            0x40 => Ok(Upload),
+            0x43 | 0x47 | 0x4b | 0x4f => Ok(UploadResponse(len)),
+            // expedited, size not indicated
+            0x42 => Ok(UploadResponse(4)),
+            0x41 => Ok(UploadSegmented),
+            0x23 | 0x27 | 0x2b | 0x2f => Ok(Download(len)),
+            0x60 => Ok(DownloadResponse),
+            0x80 => Ok(Abort),
+            other => Err(IndraError::SdoProtocol(other)),
+        }
+    }
+}
+
+/// SDO abort code from a 0x80 response, CiA 301
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AbortCode(pub u32);
+
+impl AbortCode {
+    pub fn description(&self) -> &'static str {
+        match self.0 {
+            0x0503_0000 => "Toggle bit not alternated",
+            0x0504_0000 => "SDO protocol timed out",
+            0x0504_0001 => "Command specifier not valid",
+            0x0504_0005 => "Out of memory",
+            0x0601_0000 => "Unsupported access to an object",
+            0x0601_0001 => "Attempt to read a write only object",
+            0x0601_0002 => "Attempt to write a read only object",
+            0x0602_0000 => "Object does not exist",
+            0x0604_0041 => "Object cannot be mapped to the PDO",
+            0x0604_0047 => "General internal incompatibility",
+            0x0606_0000 => "Access failed due to a hardware error",
+            0x0607_0010 => "Data type or length does not match",
+            0x0609_0011 => "Sub-index does not exist",
+            0x0609_0030 => "Value range exceeded",
+            0x0609_0031 => "Value too high",
+            0x0609_0032 => "Value too low",
+            0x0800_0000 => "General error",
+            0x0800_0020 => "Data cannot be transferred or stored",
+            0x0800_0021 => "Data cannot be transferred or stored, local control",
+            0x0800_0022 => "Data cannot be transferred or stored, device state",
+            _ => "Unknown abort code",
+        }
+    }
+}
+
+impl std::fmt::Display for AbortCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:08x} {}", self.0, self.description())
+    }
+}
+
+/// Expedited SDO frame, request or response
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SdoFrame {
+    pub command: Command,
+    pub index: u16,
+    pub subindex: u8,
+    pub data: [u8; 4],
+}
+
+impl SdoFrame {
+    pub fn upload(register: Register) -> Self {
+        Self {
+            command: Command::Upload,
+            index: register.index(),
+            subindex: register.subindex(),
+            data: [0; 4],
+        }
+    }
+    pub fn download<T: SdoValue>(register: Register, value: T) -> Self {
+        Self {
+            command: Command::Download(T::SIZE),
+            index: register.index(),
+            subindex: register.subindex(),
+            data: value.to_data(),
+        }
+    }
+    pub fn register(&self) -> Register {
+        Register::from(self.index)
+    }
+    /// Response belongs to a request for the same object
+    pub fn answers(&self, request: &SdoFrame) -> bool {
+        self.index == request.index && self.subindex == request.subindex
+    }
+    pub fn to_can(&self, node: u8) -> CANFrame {
+        let [i0, i1] = self.index.to_le_bytes();
+        let [d0, d1, d2, d3] = self.data;
+        let data = [self.command.into(), i0, i1, self.subindex, d0, d1, d2, d3];
+        CANFrame::new(SDO_REQUEST + node as u32, &data, false, false).unwrap()
+    }
+}
+
+impl TryFrom<&[u8]> for SdoFrame {
+    type Error = IndraError;
+    fn try_from(s: &[u8]) -> Result<Self, Self::Error> {
+        let s: [u8; 8] = s.try_into().map_err(|_| IndraError::BadSlice)?;
+        Ok(Self {
+            command: Command::try_from(s[0])?,
+            index: u16::from_le_bytes([s[1], s[2]]),
+            subindex: s[3],
+            data: [s[4], s[5], s[6], s[7]],
+        })
+    }
+}
+
+impl std::fmt::Display for SdoFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} {:?}.{} {:02x?}",
+            self.command,
+            self.register(),
+            self.subindex,
+            self.data
+        )
+    }
+}
+
+/// Register value types carried in an expedited transfer
+pub trait SdoValue: Sized {
+    const SIZE: u8;
+    fn to_data(&self) -> [u8; 4];
+    fn from_data(data: [u8; 4]) -> Self;
+}
+
+impl SdoValue for u8 {
+    const SIZE: u8 = 1;
+    fn to_data(&self) -> [u8; 4] {
+        [*self, 0, 0, 0]
+    }
+    fn from_data(data: [u8; 4]) -> Self {
+        data[0]
+    }
+}
+impl SdoValue for u16 {
+    const SIZE: u8 = 2;
+    fn to_data(&self) -> [u8; 4] {
+        (*self as u32).to_le_bytes()
+    }
+    fn from_data(data: [u8; 4]) -> Self {
+        u16::from_le_bytes([data[0], data[1]])
+    }
+}
+impl SdoValue for i16 {
+    const SIZE: u8 = 2;
+    fn to_data(&self) -> [u8; 4] {
+        // Pre expects the unused bytes sign extended
+        (*self as i32).to_le_bytes()
+    }
+    fn from_data(data: [u8; 4]) -> Self {
+        i16::from_le_bytes([data[0], data[1]])
+    }
+}
+impl SdoValue for u32 {
+    const SIZE: u8 = 4;
+    fn to_data(&self) -> [u8; 4] {
+        self.to_le_bytes()
+    }
+    fn from_data(data: [u8; 4]) -> Self {
+        u32::from_le_bytes(data)
+    }
+}
+impl SdoValue for [u8; 4] {
+    const SIZE: u8 = 4;
+    fn to_data(&self) -> [u8; 4] {
+        *self
+    }
+    fn from_data(data: [u8; 4]) -> Self {
+        data
+    }
+}
+
+/// Expedited SDO client for one node, frames from other nodes are ignored
+pub struct SdoClient {
+    socket: CANSocket,
+    node: u8,
+    timeout: Duration,
+    retries: u8,
+}
+
+impl SdoClient {
+    pub fn open(ifname: &str, node: u8) -> Result<Self, IndraError> {
+        let socket = CANSocket::open(ifname).map_err(|e| IndraError::CanOpen(e))?;
+        Ok(Self {
+            socket,
+            node,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+        })
+    }
+    pub fn node(&self) -> u8 {
+        self.node
+    }
+    /// Response timeout per attempt
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout
+    }
+    /// Resends after a timeout, aborts are never retried
+    pub fn set_retries(&mut self, retries: u8) {
+        self.retries = retries
+    }
+
+    pub async fn read<T: SdoValue>(&mut self, register: Register) -> Result<T, IndraError> {
+        let response = self.request(SdoFrame::upload(register)).await?;
+        match response.command {
+            Command::UploadResponse(_) => Ok(T::from_data(response.data)),
+            other => Err(IndraError::SdoProtocol(other.into())),
+        }
+    }
+
+    /// Visible string by expedited or segmented upload, trailing NULs removed
+    pub async fn read_string(&mut self, register: Register) -> Result<String, IndraError> {
+        let response = self.request(SdoFrame::upload(register)).await?;
+        let bytes = match response.command {
+            Command::UploadResponse(len) => response.data[..len as usize].to_vec(),
+            Command::UploadSegmented => {
+                let size = u32::from_data(response.data) as usize;
+                let bytes = self.upload_segments(register).await?;
+                if size != 0 && bytes.len() != size {
+                    log::warn!("Pre {register:?} {} of {size} bytes", bytes.len());
+                }
+                bytes
+            }
+            other => return Err(IndraError::SdoProtocol(other.into())),
+        };
+        Ok(String::from_utf8_lossy(&bytes)
+            .trim_end_matches('\0')
+            .trim()
+            .to_string())
+    }
+
+    async fn upload_segments(&mut self, register: Register) -> Result<Vec<u8>, IndraError> {
+        let mut bytes = Vec::new();
+        let mut toggle = false;
+        loop {
+            let request = [0x60 | ((toggle as u8) << 4), 0, 0, 0, 0, 0, 0, 0];
+            let cob_id = SDO_REQUEST + self.node as u32;
+            self.send(CANFrame::new(cob_id, &request, false, false).unwrap())
+                .await?;
+            let deadline = Instant::now() + self.timeout;
+            let (last, data) = loop {
+                let frame = self.recv(deadline).await?;
+                if let Ok(abort) = SdoFrame::try_from(frame.data()) {
+                    if abort.command == Command::Abort && abort.index == register.index() {
+                        let code = AbortCode(u32::from_data(abort.data));
+                        return Err(IndraError::SdoAbort(register, code));
+                    }
+                }
+                if let Some((last, data)) = upload_segment(frame.data(), toggle) {
+                    break (last, data.to_vec());
+                }
+            };
+            bytes.extend(data);
+            if last || bytes.len() >= MAX_SEGMENTED {
+                return Ok(bytes);
+            }
+            toggle = !toggle;
+        }
+    }
+
+    pub async fn write<T: SdoValue>(
+        &mut self,
+        register: Register,
+        value: T,
+    ) -> Result<(), IndraError> {
+        let response = self.request(SdoFrame::download(register, value)).await?;
+        match response.command {
+            Command::DownloadResponse => Ok(()),
+            other => Err(IndraError::SdoProtocol(other.into())),
+        }
+    }
+
+    async fn request(&mut self, request: SdoFrame) -> Result<SdoFrame, IndraError> {
+        let mut attempt = 0;
+        loop {
+            match self.transfer(&request).await {
+                Err(IndraError::CanBusRxTimeout(n)) if attempt < self.retries => {
+                    attempt += 1;
+                    log::debug!("Pre SDO retry {attempt} {request} (can{n} timeout)");
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sends a request without waiting, answers come from next_response
+    pub async fn submit(&mut self, request: &SdoFrame) -> Result<(), IndraError> {
+        log::trace!("Tx>>Pre {request}");
+        self.send(request.to_can(self.node)).await
+    }
+
+    /// Next decodable SDO frame before deadline
+    pub async fn next_response(&mut self, deadline: Instant) -> Result<SdoFrame, IndraError> {
+        loop {
+            let frame = self.recv(deadline).await?;
+            match SdoFrame::try_from(frame.data()) {
+                Ok(response) => {
+                    log::trace!("Rx<<Pre {response}");
+                    return Ok(response);
+                }
+                Err(e) => log::warn!("Rx<<Pre undecodable {:02x?} {e}", frame.data()),
+            }
+        }
+    }
+
+    /// Sends request and waits for the response to the same index, stale responses are dropped
+    async fn transfer(&mut self, request: &SdoFrame) -> Result<SdoFrame, IndraError> {
+        log::trace!("Tx>>Pre {request}");
+        self.send(request.to_can(self.node)).await?;
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let frame = self.recv(deadline).await?;
+            let response = match SdoFrame::try_from(frame.data()) {
+                Ok(response) => response,
+                Err(e) => {
+                    log::warn!("Rx<<Pre undecodable {:02x?} {e}", frame.data());
+                    continue;
+                }
+            };
+            log::trace!("Rx<<Pre {response}");
+            if !response.answers(request) {
+                log::debug!("Rx<<Pre dropped uncorrelated {response}");
+                continue;
+            }
+            if response.command == Command::Abort {
+                let code = AbortCode(u32::from_data(response.data));
+                return Err(IndraError::SdoAbort(request.register(), code));
+            }
+            return Ok(response);
+        }
+    }
+
+    async fn send(&mut self, frame: CANFrame) -> Result<(), IndraError> {
+        self.socket
+            .write_frame(frame)
+            .map_err(|e| IndraError::CanBusWrite(0, e))?
+            .await
+            .map_err(|e| IndraError::CanBusWriteIo(0, e))
+    }
+
+    /// Next frame on this node's response COB-ID, queued frames are taken even past the deadline
+    async fn recv(&mut self, deadline: Instant) -> Result<CANFrame, IndraError> {
+        loop {
+            match tokio::select! {
+                biased;
+                rx = self.socket.next() => rx,
+                _ = tokio::time::sleep_until(deadline) => None
+            } {
+                Some(Ok(frame)) if frame.id() == SDO_RESPONSE + self.node as u32 => {
+                    return Ok(frame)
+                }
+                Some(Ok(_)) => (),
+                _ => return Err(IndraError::CanBusRxTimeout(0)),
+            }
+        }
+    }
+}
+
+/// Upload segment response (last, data), None if not the segment expected for toggle
+fn upload_segment(data: &[u8], toggle: bool) -> Option<(bool, &[u8])> {
+    let first = *data.first()?;
+    if data.len() != 8 || first & 0xe0 != 0 || (first & 0x10 != 0) != toggle {
+        return None;
+    }
+    let unused = ((first >> 1) & 0x7) as usize;
+    Some((first & 0x1 != 0, &data[1..8 - unused]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn command_test() {
+        for byte in [
+            0x40, 0x43, 0x47, 0x4b, 0x4f, 0x23, 0x27, 0x2b, 0x2f, 0x60, 0x80,
+        ] {
+            let cmd = Command::try_from(byte).unwrap();
+            assert_eq!(u8::from(cmd), byte);
+        }
+        assert_eq!(Command::try_from(0x4b).unwrap(), Command::UploadResponse(2));
+        assert_eq!(Command::try_from(0x2f).unwrap(), Command::Download(1));
+        assert!(Command::try_from(0x44).is_err());
+    }
+    #[test]
+    fn frame_test() {
+        // matches the hand built PreCommand frames
+        let frame = SdoFrame::download(Register::DcBusMaxVsetpoint, 3700u16).to_can(PRE_NODE);
+        assert_eq!(frame.data(), &[0x2b, 0x9, 0x21, 0, 0x74, 0x0e, 0, 0]);
+        let frame = SdoFrame::download(Register::DcBusMaxAsetpoint, -15i16).to_can(PRE_NODE);
+        assert_eq!(frame.data(), &[0x2b, 0xa, 0x21, 0, 0xf1, 0xff, 0xff, 0xff]);
+        let frame = SdoFrame::upload(Register::Status).to_can(PRE_NODE);
+        assert_eq!(frame.data(), &[0x40, 0x1, 0x21, 0, 0, 0, 0, 0]);
+        assert_eq!(frame.id(), 0x630);
+        assert_eq!(SdoFrame::upload(Register::Status).to_can(0x31).id(), 0x631);
+    }
+    #[test]
+    fn response_test() {
+        let request = SdoFrame::upload(Register::DcOutputV);
+        let response = SdoFrame::try_from(&[0x4b, 0x7, 0x21, 0, 0x74, 0x0e, 0, 0][..]).unwrap();
+        assert!(response.answers(&request));
+        assert_eq!(u16::from_data(response.data), 3700);
+        let stale = SdoFrame::try_from(&[0x4b, 0x8, 0x21, 0, 0, 0, 0, 0][..]).unwrap();
+        assert!(!stale.answers(&request));
+        assert!(SdoFrame::try_from(&[0x4b, 0x8, 0x21][..]).is_err());
+    }
+    #[test]
+    fn upload_segment_test() {
+        let first = [0x00, b'P', b'R', b'E', b'-', b'3', b'0', b'k'];
+        assert_eq!(upload_segment(&first, false), Some((false, &first[1..])));
+        assert_eq!(upload_segment(&first, true), None);
+        // last segment, 4 unused bytes
+        let last = [0x19, b'W', b'-', b'1', 0, 0, 0, 0];
+        assert_eq!(upload_segment(&last, true), Some((true, &last[1..4])));
+        // not a segment response
+        assert_eq!(upload_segment(&[0x4b, 0, 0x21, 0, 0, 0, 0, 0], false), None);
+        assert_eq!(Command::try_from(0x41).unwrap(), Command::UploadSegmented);
+    }
+    #[test]
+    fn abort_test() {
+        let abort = SdoFrame::try_from(&[0x80, 0x9, 0x21, 0, 0x30, 0, 0x09, 0x06][..]).unwrap();
+        assert_eq!(abort.command, Command::Abort);
+        let code = AbortCode(u32::from_data(abort.data));
+        assert_eq!(code, AbortCode(0x0609_0030));
+        assert_eq!(code.description(), "Value range exceeded");
+        assert_eq!(AbortCode(1).description(), "Unknown abort code");
+    }
+}
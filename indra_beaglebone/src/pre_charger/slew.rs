@@ -0,0 +1,82 @@
+use crate::data_io::config::SlewConfig;
+use std::time::Duration;
+
+/// Limits how fast a setpoint moves, direction reversals stop at zero first
+#[derive(Debug, Clone, Copy)]
+pub struct SlewLimiter {
+    /// Per second, magnitude increasing
+    rise: f32,
+    /// Per second, magnitude decreasing
+    fall: f32,
+    output: f32,
+}
+
+impl SlewLimiter {
+    pub fn new(rise: f32, fall: f32, start: f32) -> Self {
+        Self {
+            rise: rise.abs(),
+            fall: fall.abs(),
+            output: start,
+        }
+    }
+    pub fn amps(config: &SlewConfig, start: f32) -> Self {
+        Self::new(config.amps_rise, config.amps_fall, start)
+    }
+    pub fn volts(config: &SlewConfig, start: f32) -> Self {
+        Self::new(config.volts_rate, config.volts_rate, start)
+    }
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+    /// Moves towards target by at most the rate over dt
+    pub fn step(&mut self, target: f32, dt: Duration) -> f32 {
+        // charge <-> discharge must pass through zero
+        let target = match self.output * target < 0.0 {
+            true => 0.0,
+            false => target,
+        };
+        let rate = match target.abs() > self.output.abs() {
+            true => self.rise,
+            false => self.fall,
+        };
+        let max = rate * dt.as_secs_f32();
+        self.output += (target - self.output).clamp(-max, max);
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    const T100MS: Duration = Duration::from_millis(100);
+    #[test]
+    fn ramp_test() {
+        let mut amps = SlewLimiter::new(10.0, 20.0, 0.0);
+        assert_eq!(amps.step(16.0, T100MS), 1.0);
+        for _ in 0..14 {
+            amps.step(16.0, T100MS);
+        }
+        assert_eq!(amps.output(), 15.0);
+        assert_eq!(amps.step(16.0, T100MS), 16.0);
+        assert_eq!(amps.step(16.0, T100MS), 16.0);
+        // falls at the faster rate
+        assert_eq!(amps.step(0.0, T100MS), 14.0);
+    }
+    #[test]
+    fn reversal_test() {
+        let mut amps = SlewLimiter::new(100.0, 100.0, 5.0);
+        // full discharge request stops at zero before reversing
+        assert_eq!(amps.step(-16.0, T100MS), 0.0);
+        assert_eq!(amps.step(-16.0, T100MS), -10.0);
+        assert_eq!(amps.step(-16.0, T100MS), -16.0);
+        assert_eq!(amps.step(16.0, T100MS), -6.0);
+        assert_eq!(amps.step(16.0, T100MS), 0.0);
+        assert_eq!(amps.step(16.0, T100MS), 10.0);
+    }
+    #[test]
+    fn volts_test() {
+        let mut volts = SlewLimiter::new(20.0, 20.0, 370.0);
+        assert_eq!(volts.step(380.0, T100MS), 372.0);
+        assert_eq!(volts.step(360.0, T100MS), 370.0);
+    }
+}
@@ -0,0 +1,317 @@
+use crate::data_io::config::{PreStatusConfig, StatusFlag};
+use serde::{ser::SerializeStruct, Deserialize, Serialize};
+use std::{
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+/// Amps scaling while a derating fault is active
+const DERATE_FACTOR: f32 = 0.5;
+/// Retryable faults that outlast this stop the session
+const RETRY_LIMIT: Duration = Duration::from_secs(30);
+
+/// How the charge loop responds to a Pre fault, most severe wins
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FaultAction {
+    Derate,
+    Retry,
+    Stop,
+}
+
+/// Status word bit mask, name & response, None is logged only
+type Flag = (u16, String, Option<FaultAction>);
+
+static FLAGS: OnceLock<Vec<Flag>> = OnceLock::new();
+
+/// Sets the status bit meanings from config.toml, call before the Pre is polled
+pub fn init(config: &PreStatusConfig) {
+    if FLAGS.set(flags(config)).is_err() {
+        log::error!("Pre status flags already set");
+    }
+}
+
+fn flags(config: &PreStatusConfig) -> Vec<Flag> {
+    config
+        .flags
+        .iter()
+        .filter_map(|flag| match 1u16.checked_shl(flag.bit as u32) {
+            Some(mask) => Some((mask, flag.name.clone(), flag.action)),
+            None => {
+                log::error!("Pre status bit {} out of range, ignored", flag.bit);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Configured status bits, defaults until init
+fn configured() -> &'static [Flag] {
+    FLAGS.get_or_init(|| flags(&PreStatusConfig::default()))
+}
+
+/// Pre 0x2101 status word, first byte is the low byte
+///
+/// The default bit positions are unconfirmed, check them against the module
+/// documentation & override in the [pre_status] section of config.toml
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub struct PreStatus(u16);
+
+impl PreStatus {
+    pub const OVER_TEMPERATURE: u16 = 1 << 0;
+    pub const AC_UNDER_VOLTAGE: u16 = 1 << 1;
+    pub const AC_OVER_VOLTAGE: u16 = 1 << 2;
+    pub const DC_OVER_VOLTAGE: u16 = 1 << 3;
+    pub const DC_UNDER_VOLTAGE: u16 = 1 << 4;
+    pub const DC_OVER_CURRENT: u16 = 1 << 5;
+    pub const INTERNAL_FAULT: u16 = 1 << 6;
+    pub const FAN_FAULT: u16 = 1 << 7;
+    pub const AC_FREQUENCY: u16 = 1 << 8;
+    pub const DC_BUS_FAULT: u16 = 1 << 9;
+    pub const TEMPERATURE_DERATING: u16 = 1 << 10;
+    pub const COMMS_TIMEOUT: u16 = 1 << 11;
+
+    const DEFAULTS: [(u16, &'static str); 12] = [
+        (Self::OVER_TEMPERATURE, "Over temperature"),
+        (Self::AC_UNDER_VOLTAGE, "AC under voltage"),
+        (Self::AC_OVER_VOLTAGE, "AC over voltage"),
+        (Self::DC_OVER_VOLTAGE, "DC over voltage"),
+        (Self::DC_UNDER_VOLTAGE, "DC under voltage"),
+        (Self::DC_OVER_CURRENT, "DC over current"),
+        (Self::INTERNAL_FAULT, "Internal fault"),
+        (Self::FAN_FAULT, "Fan fault"),
+        (Self::AC_FREQUENCY, "AC frequency out of range"),
+        (Self::DC_BUS_FAULT, "DC bus fault"),
+        (Self::TEMPERATURE_DERATING, "Temperature derating"),
+        (Self::COMMS_TIMEOUT, "Comms timeout"),
+    ];
+    /// Bit names used when config.toml lists none, logged only until a documented map exists
+    pub fn default_flags() -> Vec<StatusFlag> {
+        Self::DEFAULTS
+            .iter()
+            .map(|(mask, name)| StatusFlag {
+                bit: mask.trailing_zeros() as u8,
+                name: name.to_string(),
+                action: None,
+            })
+            .collect()
+    }
+
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+    pub fn is_ok(&self) -> bool {
+        self.0 == 0
+    }
+    pub fn contains(&self, flag: u16) -> bool {
+        self.0 & flag == flag
+    }
+    /// Bits set that have no configured meaning
+    pub fn unknown(&self) -> u16 {
+        self.unknown_in(configured())
+    }
+    pub fn faults(&self) -> Vec<&'static str> {
+        self.faults_in(configured())
+    }
+    /// Most severe response required, unknown & log only bits give None
+    pub fn action(&self) -> Option<FaultAction> {
+        self.action_in(configured())
+    }
+    fn unknown_in(&self, flags: &[Flag]) -> u16 {
+        let known = flags.iter().fold(0, |known, (flag, ..)| known | flag);
+        self.0 & !known
+    }
+    fn faults_in<'a>(&self, flags: &'a [Flag]) -> Vec<&'a str> {
+        flags
+            .iter()
+            .filter(|(flag, ..)| self.contains(*flag))
+            .map(|(_, name, _)| name.as_str())
+            .collect()
+    }
+    fn action_in(&self, flags: &[Flag]) -> Option<FaultAction> {
+        flags
+            .iter()
+            .filter(|(flag, ..)| self.contains(*flag))
+            .filter_map(|(.., action)| *action)
+            .max()
+    }
+}
+
+impl From<[u8; 2]> for PreStatus {
+    fn from(value: [u8; 2]) -> Self {
+        Self(u16::from_le_bytes(value))
+    }
+}
+
+impl std::fmt::Debug for PreStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PreStatus({:04x} {:?})", self.0, self.faults())
+    }
+}
+
+impl std::fmt::Display for PreStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_ok() {
+            return write!(f, "OK");
+        }
+        write!(f, "{}", self.faults().join(", "))?;
+        if self.unknown() != 0 {
+            write!(f, " unknown bits {:04x}", self.unknown())?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for PreStatus {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("PreStatus", 4)?;
+        s.serialize_field("bits", &self.0)?;
+        s.serialize_field("faults", &self.faults())?;
+        s.serialize_field("unknown", &self.unknown())?;
+        s.serialize_field("action", &self.action())?;
+        s.end()
+    }
+}
+
+/// Charge loop response to the current Pre status
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reaction {
+    None,
+    /// Scale requested amps
+    Derate(f32),
+    /// Zero amps while waiting for the fault to clear
+    Hold,
+    Stop,
+}
+
+/// Tracks how long retryable faults have been present
+#[derive(Default, Debug)]
+pub struct FaultHandler {
+    last: PreStatus,
+    retry_since: Option<Instant>,
+}
+
+impl FaultHandler {
+    pub fn update(&mut self, status: PreStatus, now: Instant) -> Reaction {
+        self.react(status, status.action(), now)
+    }
+    fn react(&mut self, status: PreStatus, action: Option<FaultAction>, now: Instant) -> Reaction {
+        if status != self.last {
+            match action {
+                Some(action) => log::warn!("Pre status {status} ({action:?})"),
+                None if status.is_ok() => log::info!("Pre status cleared"),
+                None => log::warn!("Pre status {status}, no action"),
+            }
+            self.last = status;
+        }
+        if action != Some(FaultAction::Retry) {
+            self.retry_since = None;
+        }
+        match action {
+            None => Reaction::None,
+            Some(FaultAction::Derate) => Reaction::Derate(DERATE_FACTOR),
+            Some(FaultAction::Retry) => {
+                let since = *self.retry_since.get_or_insert(now);
+                match now.duration_since(since) > RETRY_LIMIT {
+                    true => Reaction::Stop,
+                    false => Reaction::Hold,
+                }
+            }
+            Some(FaultAction::Stop) => Reaction::Stop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    /// Map a user might opt into in config.toml
+    fn acting() -> Vec<Flag> {
+        let flag = |mask: u16, action| (mask, String::new(), Some(action));
+        vec![
+            flag(PreStatus::OVER_TEMPERATURE, FaultAction::Derate),
+            flag(PreStatus::AC_UNDER_VOLTAGE, FaultAction::Retry),
+            flag(PreStatus::DC_OVER_VOLTAGE, FaultAction::Stop),
+            flag(PreStatus::INTERNAL_FAULT, FaultAction::Stop),
+        ]
+    }
+    #[test]
+    fn status_decode_test() {
+        let status = PreStatus::from([0x09, 0x00]);
+        assert!(status.contains(PreStatus::OVER_TEMPERATURE));
+        assert!(status.contains(PreStatus::DC_OVER_VOLTAGE));
+        assert_eq!(status.faults(), vec!["Over temperature", "DC over voltage"]);
+        // defaults are log only
+        assert_eq!(status.action(), None);
+        assert_eq!(status.action_in(&acting()), Some(FaultAction::Stop));
+
+        let status = PreStatus::from([0x00, 0x04]);
+        assert_eq!(status.faults(), vec!["Temperature derating"]);
+        assert_eq!(status.action(), None);
+
+        assert!(PreStatus::from([0, 0]).is_ok());
+        assert_eq!(PreStatus::from([0, 0]).action(), None);
+
+        let unknown = PreStatus::from([0x00, 0x80]);
+        assert_eq!(unknown.unknown(), 0x8000);
+        assert_eq!(unknown.action(), None);
+    }
+    #[test]
+    fn configured_flags_test() {
+        let config = PreStatusConfig {
+            flags: vec![
+                StatusFlag {
+                    bit: 15,
+                    name: "Ground fault".into(),
+                    action: Some(FaultAction::Stop),
+                },
+                StatusFlag {
+                    bit: 16,
+                    name: "Out of range".into(),
+                    action: Some(FaultAction::Stop),
+                },
+            ],
+        };
+        let map = flags(&config);
+        assert_eq!(map.len(), 1);
+
+        let status = PreStatus::from([0x01, 0x80]);
+        assert_eq!(status.faults_in(&map), vec!["Ground fault"]);
+        assert_eq!(status.unknown_in(&map), 0x0001);
+        assert_eq!(status.action_in(&map), Some(FaultAction::Stop));
+        assert_eq!(PreStatus::from([0x01, 0x00]).action_in(&map), None);
+
+        // defaults round trip through config
+        let defaults = PreStatusConfig::default();
+        assert_eq!(defaults.flags.len(), 12);
+        assert_eq!(
+            PreStatus::from([0x00, 0x04]).faults_in(&flags(&defaults)),
+            vec!["Temperature derating"]
+        );
+    }
+    #[test]
+    fn fault_handler_test() {
+        let mut handler = FaultHandler::default();
+        let map = acting();
+        let mut update =
+            |status: PreStatus, now| handler.react(status, status.action_in(&map), now);
+        let start = Instant::now();
+        let ok = PreStatus::default();
+        let ac = PreStatus(PreStatus::AC_UNDER_VOLTAGE);
+        let hot = PreStatus(PreStatus::OVER_TEMPERATURE);
+
+        assert_eq!(update(ok, start), Reaction::None);
+        assert_eq!(update(hot, start), Reaction::Derate(DERATE_FACTOR));
+        assert_eq!(update(ac, start), Reaction::Hold);
+        assert_eq!(update(ac, start + Duration::from_secs(10)), Reaction::Hold);
+        // cleared fault resets the retry window
+        assert_eq!(update(ok, start + Duration::from_secs(20)), Reaction::None);
+        assert_eq!(update(ac, start + Duration::from_secs(40)), Reaction::Hold);
+        assert_eq!(update(ac, start + Duration::from_secs(71)), Reaction::Stop);
+        assert_eq!(
+            update(PreStatus(PreStatus::INTERNAL_FAULT), start),
+            Reaction::Stop
+        );
+        // unlisted bits don't end the session
+        assert_eq!(update(PreStatus(0x8000), start), Reaction::None);
+    }
+}
@@ -0,0 +1,126 @@
+use super::Register;
+use serde::Serialize;
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+/// Pre link health from consecutive unanswered reads
+#[derive(Serialize, Default, Debug, Clone, Copy, PartialEq)]
+pub enum CommsState {
+    #[default]
+    Ok,
+    /// Some reads unanswered, below the miss limit
+    Degraded,
+    Lost,
+    /// Power cycling & reinitialising after a loss
+    Recovering,
+}
+
+impl CommsState {
+    /// Pre data can't be trusted, any session must stop
+    pub fn is_lost(&self) -> bool {
+        matches!(self, CommsState::Lost | CommsState::Recovering)
+    }
+}
+
+#[derive(Serialize, Default, Debug, Clone, Copy, PartialEq)]
+pub struct CommsStatus {
+    pub state: CommsState,
+    pub restarts: u8,
+    /// Age of the oldest register answer
+    pub stale_ms: u64,
+}
+
+#[derive(Default, Debug)]
+struct Freshness {
+    last: Option<Instant>,
+    misses: u8,
+}
+
+/// Tracks response freshness for each polled register
+#[derive(Debug)]
+pub struct Supervisor {
+    max_misses: u8,
+    registers: HashMap<u16, Freshness>,
+    restarts: u8,
+}
+
+impl Supervisor {
+    pub fn new(max_misses: u8) -> Self {
+        Self {
+            max_misses: max_misses.max(1),
+            registers: HashMap::new(),
+            restarts: 0,
+        }
+    }
+    /// Reads that got any response count as answered, SDO aborts included
+    pub fn record(&mut self, register: Register, answered: bool, now: Instant) {
+        let freshness = self.registers.entry(register.index()).or_default();
+        match answered {
+            true => {
+                freshness.last = Some(now);
+                freshness.misses = 0;
+            }
+            false => freshness.misses = freshness.misses.saturating_add(1),
+        }
+    }
+    pub fn state(&self) -> CommsState {
+        let misses = self.registers.values().map(|f| f.misses).max().unwrap_or(0);
+        match misses {
+            0 => CommsState::Ok,
+            m if m < self.max_misses => CommsState::Degraded,
+            _ => CommsState::Lost,
+        }
+    }
+    fn stale(&self, now: Instant) -> Duration {
+        self.registers
+            .values()
+            .filter_map(|f| f.last)
+            .map(|last| now.duration_since(last))
+            .max()
+            .unwrap_or_default()
+    }
+    pub fn status(&self, now: Instant) -> CommsStatus {
+        CommsStatus {
+            state: self.state(),
+            restarts: self.restarts,
+            stale_ms: self.stale(now).as_millis() as u64,
+        }
+    }
+    pub fn restarts(&self) -> u8 {
+        self.restarts
+    }
+    /// Forgets register history ahead of reinitialising
+    pub fn restart(&mut self) {
+        self.registers.clear();
+        self.restarts += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn supervisor_test() {
+        let mut supervisor = Supervisor::new(3);
+        let start = Instant::now();
+        assert_eq!(supervisor.state(), CommsState::Ok);
+        supervisor.record(Register::Temp, true, start);
+        supervisor.record(Register::Status, true, start);
+        supervisor.record(Register::Status, false, start);
+        assert_eq!(supervisor.state(), CommsState::Degraded);
+        // answer resets the miss count
+        supervisor.record(Register::Status, true, start);
+        assert_eq!(supervisor.state(), CommsState::Ok);
+        for _ in 0..3 {
+            supervisor.record(Register::Temp, false, start);
+        }
+        assert_eq!(supervisor.state(), CommsState::Lost);
+        assert!(supervisor.state().is_lost());
+        let status = supervisor.status(start + Duration::from_millis(500));
+        assert_eq!(status.stale_ms, 500);
+
+        supervisor.restart();
+        assert_eq!(supervisor.state(), CommsState::Ok);
+        assert_eq!(supervisor.restarts(), 1);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::{
+    chademo::state::{C1PIN, C2PIN, D1PIN, D2PIN, MASTERCONTACTOR, PREACPIN},
+    data_io::{
+        config::{EstopConfig, APP_CONFIG},
+        wear::{self, Device},
+    },
+    error::IndraError,
+    global_state::OperationMode,
+    log_error,
+    pre_charger::PREDATA,
+    statics::ChademoTx,
+};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use sysfs_gpio::{Direction, Edge, Pin};
+use tokio::sync::RwLock;
+
+const FAULT_FILE: &str = "fault.json";
+
+lazy_static::lazy_static! {
+    /// Latched fault, persists across restarts until acknowledged
+    pub static ref FAULT: Arc<RwLock<Option<LatchedFault>>> = Arc::new(RwLock::new(load()));
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LatchedFault {
+    pub reason: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+fn load() -> Option<LatchedFault> {
+    let contents = std::fs::read_to_string(FAULT_FILE).ok()?;
+    match serde_json::from_str::<LatchedFault>(&contents) {
+        Ok(fault) => {
+            log::error!("Latched fault from previous run: {fault:?}");
+            Some(fault)
+        }
+        Err(e) => {
+            // unreadable latch file must not clear the fault
+            log::error!("Fault file unreadable {e:?}");
+            Some(LatchedFault {
+                reason: "Unreadable fault file".into(),
+                timestamp: Utc::now(),
+            })
+        }
+    }
+}
+
+fn store(fault: &LatchedFault) -> Result<(), IndraError> {
+    let json_data = serde_json::to_string(fault).map_err(|e| IndraError::Serialise(e))?;
+    std::fs::write(FAULT_FILE, json_data).map_err(|e| IndraError::FileAccess(e))
+}
+
+/// True if a fault is latched, usable outside async
+pub fn latched() -> bool {
+    FAULT.try_read().map(|f| f.is_some()).unwrap_or(true)
+}
+
+pub async fn is_latched() -> bool {
+    FAULT.read().await.is_some()
+}
+
+/// Checked before each actuator step, a trip mid session must not be undone
+pub async fn ensure_clear() -> Result<(), IndraError> {
+    match is_latched().await {
+        true => Err(IndraError::FaultLatched),
+        false => Ok(()),
+    }
+}
+
+/// Drives every power path low
+pub fn open_all() {
+    for (name, pin) in [
+        ("c1", C1PIN),
+        ("c2", C2PIN),
+        ("Pre AC", PREACPIN),
+        ("d1", D1PIN),
+        ("d2", D2PIN),
+        ("master contactor", MASTERCONTACTOR),
+    ] {
+        log_error!(format!("E-stop open {name}"), Pin::new(pin).set_value(0));
+    }
+}
+
+/// Opens all contactors and latches fault
+pub async fn latch(reason: &str) {
+    open_all();
+    // last telemetry is from before the open
+    let amps = PREDATA.lock().await.get_dc_output_amps();
+    Device::ALL
+        .into_iter()
+        .for_each(|device| wear::opened(device, amps));
+    log::error!("                                       !!!!FAULT {reason}!!!!");
+    let fault = LatchedFault {
+        reason: reason.to_string(),
+        timestamp: Utc::now(),
+    };
+    log_error!("Persist latched fault", store(&fault));
+    *FAULT.write().await = Some(fault);
+}
+
+/// Latches fault and idles the CHAdeMO thread
+pub async fn trip(reason: &str, mode_tx: &ChademoTx) {
+    latch(&format!("E-stop {reason}")).await;
+    log_error!("E-stop idle", mode_tx.send(OperationMode::Idle).await);
+}
+
+/// Clears a latched fault, refused while the e-stop input is active
+pub fn acknowledge() -> Result<(), IndraError> {
+    if input_active(&APP_CONFIG.estop) {
+        return Err(IndraError::EstopActive);
+    }
+    let mut fault = FAULT.try_write().map_err(|_| IndraError::Error)?;
+    if let Some(f) = fault.take() {
+        log::warn!("Latched fault acknowledged: {f:?}");
+    }
+    if let Err(e) = std::fs::remove_file(FAULT_FILE) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(IndraError::FileAccess(e));
+        }
+    }
+    Pin::new(MASTERCONTACTOR)
+        .set_value(1)
+        .map_err(|e| IndraError::PinAccess(e))
+}
+
+fn is_active(config: &EstopConfig, val: u8) -> bool {
+    (val == 0) == config.active_low
+}
+
+fn input_active(config: &EstopConfig) -> bool {
+    match config.pin {
+        Some(pin) => Pin::new(pin)
+            .get_value()
+            .map_or(true, |v| is_active(config, v)),
+        None => false,
+    }
+}
+
+pub async fn estop_monitor(config: EstopConfig, mode_tx: ChademoTx) -> Result<(), IndraError> {
+    let Some(pin_num) = config.pin else {
+        log::warn!("E-stop input not configured");
+        return Ok(());
+    };
+    log::info!(
+        "Starting E-stop monitor {} on gpio{pin_num}",
+        tokio::task::id()
+    );
+    let pin = Pin::new(pin_num);
+    pin.export()
+        .map_err(|_| IndraError::PinInitError(pin_num))?;
+    pin.set_direction(Direction::In)
+        .map_err(|e| IndraError::PinAccess(e))?;
+    pin.set_edge(Edge::BothEdges)
+        .map_err(|e| IndraError::PinAccess(e))?;
+
+    if input_active(&config) {
+        trip("input active at startup", &mode_tx).await;
+    }
+    let mut gpio_events = pin
+        .get_value_stream()
+        .map_err(|e| IndraError::PinAccess(e))?;
+    while let Some(evt) = gpio_events.next().await {
+        match evt {
+            Ok(val) if is_active(&config, val) => trip("input triggered", &mode_tx).await,
+            Ok(_) => log::warn!("E-stop input released, fault remains latched"),
+            Err(e) => {
+                // lost the input, fail safe
+                log::error!("E-stop input error {e:?}");
+                trip("input read failed", &mode_tx).await;
+            }
+        }
+    }
+    trip("input monitor ended", &mode_tx).await;
+    Err(IndraError::Error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn is_active_test() {
+        let low = EstopConfig {
+            pin: Some(48),
+            active_low: true,
+        };
+        assert!(is_active(&low, 0));
+        assert!(!is_active(&low, 1));
+        let high = EstopConfig {
+            pin: Some(48),
+            active_low: false,
+        };
+        assert!(is_active(&high, 1));
+        assert!(!is_active(&high, 0));
+    }
+}
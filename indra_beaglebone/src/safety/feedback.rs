@@ -0,0 +1,78 @@
+use crate::{
+    chademo::state::pin_init_input,
+    data_io::{
+        config::{FeedbackConfig, FeedbackInput},
+        wear::Device,
+    },
+    error::IndraError,
+};
+use std::time::Duration;
+use sysfs_gpio::Pin;
+use tokio::time::{sleep, Instant};
+
+const POLL: Duration = Duration::from_millis(10);
+
+/// Exports fitted feedback inputs
+pub fn init(config: &FeedbackConfig) -> Result<(), IndraError> {
+    for device in Device::ALL {
+        if let Some(input) = config.input(device) {
+            log::info!("{} feedback on gpio{}", device.name(), input.pin);
+            pin_init_input(input.pin)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_closed(input: &FeedbackInput, val: u8) -> bool {
+    (val == 0) == input.active_low
+}
+
+/// Waits for an actuator to report its commanded state, passes if no input is fitted
+pub async fn verify(
+    device: Device,
+    closed: bool,
+    config: &FeedbackConfig,
+) -> Result<(), IndraError> {
+    let Some(input) = config.input(device) else {
+        return Ok(());
+    };
+    let pin = Pin::new(input.pin);
+    let deadline = Instant::now() + Duration::from_millis(config.timeout_ms);
+    loop {
+        let val = pin.get_value().map_err(|e| IndraError::PinAccess(e))?;
+        if is_closed(input, val) == closed {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(IndraError::ActuatorFeedback(device.name(), closed));
+        }
+        sleep(POLL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn is_closed_test() {
+        let aux = FeedbackInput {
+            pin: 60,
+            active_low: false,
+        };
+        assert!(is_closed(&aux, 1));
+        assert!(!is_closed(&aux, 0));
+        let aux = FeedbackInput {
+            pin: 60,
+            active_low: true,
+        };
+        assert!(is_closed(&aux, 0));
+        assert!(!is_closed(&aux, 1));
+    }
+    #[tokio::test]
+    async fn verify_unfitted_test() {
+        let config = FeedbackConfig::default();
+        for device in Device::ALL {
+            assert!(verify(device, true, &config).await.is_ok());
+        }
+    }
+}
@@ -0,0 +1,3 @@
+pub(crate) mod estop;
+pub(crate) mod feedback;
+pub(crate) mod startup;
@@ -0,0 +1,234 @@
+use super::feedback;
+use crate::{
+    chademo::state::{
+        pin_init_input, pin_init_out_low, C1PIN, C2PIN, D1PIN, D2PIN, KPIN, PLUG_LOCK, PREACPIN,
+    },
+    data_io::{config::FeedbackConfig, wear::Device},
+    error::IndraError,
+    log_error,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use tokio::sync::RwLock;
+
+const PHASE_FILE: &str = "session.json";
+/// Pre output above this at first init means the DC bus is still live
+const SAFE_VOLTS: f32 = 60.0;
+
+lazy_static::lazy_static! {
+    pub static ref STARTUP: Arc<RwLock<StartupReport>> = Arc::new(RwLock::new(StartupReport::default()));
+    static ref PHASE: Mutex<SessionPhase> = Mutex::new(load_phase());
+}
+
+/// Phase file left as found while startup faults await acknowledgement
+static HOLD_PHASE: AtomicBool = AtomicBool::new(false);
+
+/// Session progress, persisted so a restart knows where the last run stopped
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum SessionPhase {
+    #[default]
+    Idle,
+    PreInit,
+    Handshake,
+    PlugLocked,
+    Precharge,
+    Energised,
+    Shutdown,
+    /// Phase file unreadable
+    Unknown,
+}
+
+impl SessionPhase {
+    /// DC output may be connected to the EV, assumed when unknown
+    pub fn is_energised(&self) -> bool {
+        use SessionPhase::*;
+        matches!(self, Precharge | Energised | Unknown)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StartupReport {
+    pub last_phase: SessionPhase,
+    /// Pre DC output at first init after start
+    pub output_volts: Option<f32>,
+    pub faults: Vec<String>,
+}
+
+impl StartupReport {
+    /// K line & contactors passed, output volts may still be pending
+    pub fn is_safe(&self) -> bool {
+        self.faults.is_empty()
+    }
+    pub fn confirmed(&self) -> bool {
+        self.is_safe() && self.output_volts.is_some()
+    }
+}
+
+/// Records the current session phase, written only on change
+pub fn set_phase(phase: SessionPhase) {
+    let Ok(mut current) = PHASE.lock() else {
+        return;
+    };
+    if *current == phase {
+        return;
+    }
+    *current = phase;
+    if !HOLD_PHASE.load(Ordering::Acquire) {
+        write_phase(phase);
+    }
+}
+
+fn write_phase(phase: SessionPhase) {
+    let json_data = serde_json::to_string(&phase).map_err(|e| IndraError::Serialise(e));
+    log_error!(
+        "Persist session phase",
+        json_data
+            .and_then(|d| std::fs::write(PHASE_FILE, d).map_err(|e| IndraError::FileAccess(e)))
+    );
+}
+
+pub fn phase() -> SessionPhase {
+    PHASE.lock().map(|p| *p).unwrap_or(SessionPhase::Unknown)
+}
+
+fn load_phase() -> SessionPhase {
+    match std::fs::read_to_string(PHASE_FILE) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or(SessionPhase::Unknown),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => SessionPhase::Idle,
+        Err(_) => SessionPhase::Unknown,
+    }
+}
+
+/// A run that died mid session may have left the DC bus or contactors live
+fn phase_fault(last_phase: SessionPhase) -> Option<String> {
+    match last_phase {
+        SessionPhase::Idle => None,
+        phase => Some(format!("Previous run stopped during {phase:?}")),
+    }
+}
+
+/// Drives actuators low then checks K line & contactor feedback, run before the master contactor
+///
+/// Exported gpio keeps its value if the process dies, so actuators may still be driven.
+pub async fn self_check(config: &FeedbackConfig) {
+    let mut report = StartupReport {
+        last_phase: load_phase(),
+        ..Default::default()
+    };
+    if let Some(fault) = phase_fault(report.last_phase) {
+        report.faults.push(fault);
+    }
+
+    for pin in [C1PIN, C2PIN, PREACPIN, D1PIN, D2PIN, PLUG_LOCK] {
+        if let Err(e) = pin_init_out_low(pin) {
+            report.faults.push(format!("{e}"));
+        }
+    }
+
+    match pin_init_input(KPIN).map(|k| k.get_value()) {
+        Ok(Ok(1)) => (),
+        Ok(Ok(_)) => report.faults.push("K line active".into()),
+        Ok(Err(e)) => report
+            .faults
+            .push(format!("K line {}", IndraError::PinAccess(e))),
+        Err(e) => report.faults.push(format!("K line {e}")),
+    }
+
+    for device in Device::ALL {
+        if let Err(e) = feedback::verify(device, false, config).await {
+            report.faults.push(format!("{e}"));
+        }
+    }
+
+    for fault in report.faults.iter() {
+        log::error!("Startup fault: {fault}");
+    }
+    // keeps the unfinished phase on disk across restarts until acknowledged
+    HOLD_PHASE.store(!report.faults.is_empty(), Ordering::Release);
+    set_phase(SessionPhase::Idle);
+    *STARTUP.write().await = report;
+}
+
+/// Clears startup faults once the hardware has been checked, usable outside async
+pub fn acknowledge() -> Result<(), IndraError> {
+    let mut report = STARTUP.try_write().map_err(|_| IndraError::Error)?;
+    for fault in report.faults.drain(..) {
+        log::warn!("Startup fault acknowledged: {fault}");
+    }
+    if HOLD_PHASE.swap(false, Ordering::AcqRel) {
+        write_phase(phase());
+    }
+    Ok(())
+}
+
+/// Checks Pre output before it is driven, only the first init after start is recorded
+pub async fn check_output_volts(volts: f32) -> Result<(), IndraError> {
+    let mut report = STARTUP.write().await;
+    if report.output_volts.is_none() {
+        report.output_volts = Some(volts);
+        if volts > SAFE_VOLTS {
+            report
+                .faults
+                .push(format!("Output {volts:.0}V at first Pre init"));
+        }
+    }
+    match report.is_safe() {
+        true => Ok(()),
+        false => Err(IndraError::StartupFault),
+    }
+}
+
+/// No faults, Pre output may not be checked yet
+pub async fn is_safe() -> bool {
+    STARTUP.read().await.is_safe()
+}
+
+/// No faults & Pre output checked
+pub async fn is_confirmed() -> bool {
+    STARTUP.read().await.confirmed()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[tokio::test]
+    async fn check_output_volts_test() {
+        assert!(!STARTUP.read().await.confirmed());
+        assert!(check_output_volts(2.0).await.is_ok());
+        assert!(STARTUP.read().await.confirmed());
+        // only first init is recorded
+        assert!(check_output_volts(370.0).await.is_ok());
+        assert_eq!(STARTUP.read().await.output_volts, Some(2.0));
+
+        STARTUP.write().await.output_volts = None;
+        assert!(check_output_volts(380.0).await.is_err());
+        assert!(!STARTUP.read().await.is_safe());
+        // faulted until acknowledged
+        assert!(check_output_volts(2.0).await.is_err());
+        assert!(acknowledge().is_ok());
+        assert!(check_output_volts(2.0).await.is_ok());
+    }
+    #[test]
+    fn phase_fault_test() {
+        assert_eq!(phase_fault(SessionPhase::Idle), None);
+        for phase in [
+            SessionPhase::Precharge,
+            SessionPhase::Energised,
+            SessionPhase::Unknown,
+        ] {
+            assert!(phase_fault(phase).is_some());
+        }
+    }
+    #[test]
+    fn phase_serde_test() {
+        let json = serde_json::to_string(&SessionPhase::Energised).unwrap();
+        assert_eq!(json, r#""Energised""#);
+        assert_eq!(
+            serde_json::from_str::<SessionPhase>(&json).unwrap(),
+            SessionPhase::Energised
+        );
+    }
+}